@@ -0,0 +1,51 @@
+// 連続してアンダーランが起きたら、上限までstep_msずつレイテンシを増やす
+const REQUIRED_STREAK: usize = 2;
+
+pub struct LatencyAdapter {
+    latency_ms: usize,
+    step_ms: usize,
+    max_ms: usize,
+    streak: usize,
+}
+
+impl LatencyAdapter {
+    pub fn new(latency_ms: usize, step_ms: usize, max_ms: usize) -> Self {
+        Self {
+            latency_ms,
+            step_ms,
+            max_ms,
+            streak: 0,
+        }
+    }
+
+    // 監視周期ごとにアンダーランの有無を渡す。レイテンシを上げるときは新しい値を返す
+    pub fn observe(&mut self, underrun: bool) -> Option<usize> {
+        if !underrun {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        if self.streak < REQUIRED_STREAK || self.latency_ms >= self.max_ms {
+            return None;
+        }
+        self.streak = 0;
+        self.latency_ms = (self.latency_ms + self.step_ms).min(self.max_ms);
+        Some(self.latency_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_underruns_step_latency_up_to_the_cap() {
+        let mut adapter = LatencyAdapter::new(20, 10, 45);
+        let steps: Vec<Option<usize>> = [true, false, true, true, true, true, true, true, true, true]
+            .into_iter()
+            .map(|underrun| adapter.observe(underrun))
+            .collect();
+        // 単発のアンダーランは無視し、2回続いたら上げる。上限で止まる
+        assert_eq!(steps, [None, None, None, Some(30), None, Some(40), None, Some(45), None, None]);
+    }
+}
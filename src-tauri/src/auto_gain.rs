@@ -0,0 +1,82 @@
+use std::f64::consts::SQRT_2;
+
+const RMS_WINDOW_MS: f64 = 50.0;
+const ATTACK_MS: f64 = 5.0;
+const RELEASE_MS: f64 = 1000.0;
+
+pub struct AutoGain {
+    threshold: f64,
+    rms_coeff: f64,
+    attack_coeff: f64,
+    release_coeff: f64,
+    mean_sq: f64,
+    gain: f64,
+}
+
+impl AutoGain {
+    pub fn new(sample_rate: f32, threshold: f64) -> Self {
+        let coeff = |ms: f64| 1.0 - (-1000.0 / (ms * sample_rate as f64)).exp();
+        Self {
+            threshold,
+            rms_coeff: coeff(RMS_WINDOW_MS),
+            attack_coeff: coeff(ATTACK_MS),
+            release_coeff: coeff(RELEASE_MS),
+            mean_sq: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    // 出力のRMSから推定したピークがしきい値を超えたらゲインを下げ、ゆっくり戻す
+    pub fn update(&mut self, [l, r]: [f32; 2]) {
+        let (l, r) = (l as f64, r as f64);
+        self.mean_sq += self.rms_coeff * ((l * l + r * r) / 2.0 - self.mean_sq);
+        let peak = (self.mean_sq.sqrt() * SQRT_2).max(l.abs()).max(r.abs());
+
+        let target = if peak > self.threshold { self.gain * self.threshold / peak } else { 1.0 };
+        let coeff = if target < self.gain { self.attack_coeff } else { self.release_coeff };
+        self.gain += (target - self.gain) * coeff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 48000.0;
+
+    // ゲインを掛けた出力をそのまま計測に戻す (出力経路と同じ順序)
+    fn run(amplitude: f64, seconds: f64) -> (AutoGain, f64) {
+        let mut ag = AutoGain::new(SR, 0.891);
+        let len = (seconds * SR as f64) as usize;
+        let mut tail_peak: f64 = 0.0;
+        for n in 0..len {
+            let x = amplitude * (2.0 * std::f64::consts::PI * 1000.0 * n as f64 / SR as f64).sin();
+            let y = (x * ag.gain()) as f32;
+            ag.update([y, y]);
+            if n >= len - SR as usize / 10 {
+                tail_peak = tail_peak.max(y.abs() as f64);
+            }
+        }
+        (ag, tail_peak)
+    }
+
+    #[test]
+    fn boosted_signal_is_brought_below_full_scale() {
+        // 打ち消しで+6 dB持ち上がった信号
+        let (ag, tail_peak) = run(2.0, 1.0);
+        assert!(tail_peak < 1.0, "peak {}", tail_peak);
+        assert!(ag.gain() < 0.5, "gain {}", ag.gain());
+    }
+
+    #[test]
+    fn quiet_signal_is_left_alone() {
+        let (ag, tail_peak) = run(0.5, 1.0);
+        assert!((ag.gain() - 1.0).abs() < 1e-9, "gain {}", ag.gain());
+        assert!((tail_peak - 0.5).abs() < 1e-3);
+    }
+}
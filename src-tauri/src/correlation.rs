@@ -0,0 +1,89 @@
+const WINDOW_MS: f64 = 300.0;
+// 無音とみなすパワーの下限。これ以下では相関を0とする
+const SILENCE: f64 = 1e-10;
+// モノラル互換度の下限 [dB] (完全な逆相で-infにならないようにする)
+const MONO_COMPAT_FLOOR_DB: f32 = -60.0;
+
+// 出力L/Rの正規化相互相関 (位相相関計)。+1で同相、0で無相関、-1で逆相
+pub struct CorrelationMeter {
+    coeff: f64,
+    lr: f64,
+    ll: f64,
+    rr: f64,
+}
+
+impl CorrelationMeter {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            coeff: 1.0 - (-1000.0 / (WINDOW_MS * sample_rate as f64)).exp(),
+            lr: 0.0,
+            ll: 0.0,
+            rr: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, [l, r]: [f32; 2]) {
+        let (l, r) = (l as f64, r as f64);
+        self.lr += (l * r - self.lr) * self.coeff;
+        self.ll += (l * l - self.ll) * self.coeff;
+        self.rr += (r * r - self.rr) * self.coeff;
+    }
+
+    pub fn value(&self) -> f32 {
+        let power = (self.ll * self.rr).sqrt();
+        if power < SILENCE {
+            return 0.0;
+        }
+        (self.lr / power).clamp(-1.0, 1.0) as f32
+    }
+
+    // L+Rのパワーと、左右それぞれのパワーの和 (の2倍) との比 [dB]。
+    // 同相で0dB、無相関で-3dB、逆相で大きく負になる
+    pub fn mono_compat_db(&self) -> f32 {
+        let power = self.ll + self.rr;
+        if power < SILENCE {
+            return 0.0;
+        }
+        let mono = (power + 2.0 * self.lr).max(0.0);
+        (10.0 * (mono / (2.0 * power)).log10() as f32).max(MONO_COMPAT_FLOOR_DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 48000.0;
+
+    fn meter(right_sign: f32) -> CorrelationMeter {
+        let mut meter = CorrelationMeter::new(SR);
+        for n in 0..SR as usize {
+            let x = (2.0 * std::f32::consts::PI * 440.0 * n as f32 / SR).sin() * 0.5;
+            meter.update([x, x * right_sign]);
+        }
+        meter
+    }
+
+    #[test]
+    fn in_phase_and_anti_phase_reach_the_extremes() {
+        assert!((meter(1.0).value() - 1.0).abs() < 1e-3, "{}", meter(1.0).value());
+        assert!((meter(-1.0).value() + 1.0).abs() < 1e-3, "{}", meter(-1.0).value());
+        // 片側でも無音なら0
+        assert_eq!(meter(0.0).value(), 0.0);
+        assert_eq!(CorrelationMeter::new(SR).value(), 0.0);
+    }
+
+    #[test]
+    fn mono_compat_of_in_phase_uncorrelated_and_anti_phase_pairs() {
+        assert!(meter(1.0).mono_compat_db().abs() < 0.01, "{}", meter(1.0).mono_compat_db());
+        assert_eq!(meter(-1.0).mono_compat_db(), MONO_COMPAT_FLOOR_DB);
+        // 周波数の違う正弦波どうしはほぼ無相関で、和のパワーは同相の半分になる
+        let mut uncorrelated = CorrelationMeter::new(SR);
+        for n in 0..SR as usize {
+            let t = 2.0 * std::f32::consts::PI * n as f32 / SR;
+            uncorrelated.update([(440.0 * t).sin() * 0.5, (613.0 * t).sin() * 0.5]);
+        }
+        assert!((uncorrelated.mono_compat_db() + 3.01).abs() < 0.2, "{}", uncorrelated.mono_compat_db());
+        assert_eq!(CorrelationMeter::new(SR).mono_compat_db(), 0.0);
+    }
+}
@@ -1,6 +1,110 @@
-use crate::filter::{Processable, PrimaryFilter, BiquadFilter};
+use crate::filter::{Processable, PrimaryFilter, BiquadFilter, BiquadCascade, Crossfaded};
+use crate::hrtf::HrtfEngine;
+use crate::smoother::LinearSmoother;
+
+// 距離・振幅係数の配列 (`[f64; 4]`) の並び
+pub const LS_TO_LE: usize = 0;
+pub const LS_TO_RE: usize = 1;
+pub const RS_TO_LE: usize = 2;
+pub const RS_TO_RE: usize = 3;
+
+// 遅延線 (512サンプル) から読むときの遅延の範囲。
+// クロストーク側の遅延線 (rb_*_90, CancelStage) は読んでから書くので、1サンプル未満だと
+// 補間の相手に現在位置、つまり512サンプル前の古い値が混ざる。主経路 (rb_*_0) は書いてから読むので0でよい。
+// 上限は補間で読む前後の点が一周して現在位置に重ならないように選ぶ
+const MIN_CT_DELAY: f64 = 1.0;
+const MIN_MAIN_DELAY: f64 = 0.0;
+const MAX_DELAY: f64 = 509.0;
+// 遅延線の線形補間で落ちる高域を補うシェルフの周波数 (ナイキスト周波数の半分を上限にする)
+const EMPHASIS_CUTOFF_HZ: f32 = 8000.0;
+// 全体の傾き (チルト) の中心周波数と、そこから上下に並べる1オクターブ間隔のシェルフの組数
+const TILT_PIVOT_HZ: f32 = 1000.0;
+const TILT_SHELF_PAIRS: i32 = 4;
+// 位置の変更で遅延が変わったとき、新しい値まで補間する時間
+const DELAY_RAMP_MS: f32 = 10.0;
+// 遮蔽ローパスの2次セクションの段数の上限 (n段で2n次のバターワース)
+pub const MAX_SHADOW_STAGES: usize = 4;
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputLimiter {
+    #[default]
+    Hard,
+    Tanh,
+    Cubic,
+}
+
+impl OutputLimiter {
+    #[inline(always)]
+    fn apply(&self, x: f64, ceiling: f64) -> f64 {
+        // 振幅をceilingで正規化してから各特性を適用する
+        let x = x / ceiling;
+        let y = match self {
+            OutputLimiter::Hard => x.clamp(-1.0, 1.0),
+            OutputLimiter::Tanh => x.tanh(),
+            OutputLimiter::Cubic => {
+                let x = x.clamp(-1.0, 1.0);
+                1.5 * x - 0.5 * x * x * x
+            }
+        };
+        y * ceiling
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    Cubic,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RenderMode {
+    #[default]
+    Transaural,
+    HeadphoneCrossfeed,
+    // 読み込んだHRIRによるバイノーラル処理 (HRIRが無ければTransauralと同じ)
+    Hrtf,
+}
+
+// デバッグ用に、直接音か打ち消し信号のどちらかだけを出す
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SoloMode {
+    #[default]
+    All,
+    DirectOnly,
+    CrosstalkOnly,
+}
+
+// 各フィルタの係数 (解析用)。_lは左→右耳、_rは右→左耳の経路の順に並べる
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineCoefficients {
+    pub low_pass: [Vec<[f64; 5]>; 2],
+    pub high_pass: [[f64; 5]; 2],
+    pub low_shelf: [[f64; 5]; 2],
+    pub allpass_a: Vec<f64>,
+    pub allpass_b: Vec<f64>,
+    pub frozen: bool,
+}
+
+// 標本化周波数を変えたときに係数を計算し直すための設計値
+struct FilterDesign {
+    allpass_poles: (Vec<f64>, Vec<f64>),
+    hp_cutoff: f32,
+    low_shelf: (f32, f32),
+    phase_align: [Option<(f32, f64)>; 2],
+    input_eq: [Vec<(f32, f32, f64)>; 2],
+    emphasis: Option<f32>,
+    tilt: f32,
+}
 
 pub struct CtcEngine {
+    sample_rate: f32,
+    design: FilterDesign,
     filter_a_l: Vec<PrimaryFilter>,
     filter_a_r: Vec<PrimaryFilter>,
     filter_b_l: Vec<PrimaryFilter>,
@@ -12,29 +116,99 @@ pub struct CtcEngine {
     main_delay_r: f64,
     rb_l_90: [f64; 512],
     rb_r_90: [f64; 512],
-    low_pass_l: BiquadFilter,
-    low_pass_r: BiquadFilter,
-    high_pass_l: BiquadFilter,
-    high_pass_r: BiquadFilter,
-    low_shelf_l: BiquadFilter,
-    low_shelf_r: BiquadFilter,
+    // 頭部による遮蔽を模したクロストーク経路のローパス。_lは左→右耳、_rは右→左耳の経路
+    low_pass_l: Crossfaded<BiquadCascade>,
+    low_pass_r: Crossfaded<BiquadCascade>,
+    lp_cutoffs: [f32; 2],
+    // 遮蔽ローパスの2次セクションの段数 ([左→右耳, 右→左耳])
+    shadow_stages: [usize; 2],
+    extra_stages: Vec<CancelStage>,
+    high_pass_l: Crossfaded<BiquadFilter>,
+    high_pass_r: Crossfaded<BiquadFilter>,
+    low_shelf_l: Crossfaded<BiquadFilter>,
+    low_shelf_r: Crossfaded<BiquadFilter>,
     ct_delay_l: f64,
     ct_delay_r: f64,
+    // 遅延を段差なく動かすための補間 ([ct_l, ct_r, main_l, main_r])
+    delay_smoothers: [LinearSmoother; 4],
+    dc_block_l: Option<PrimaryFilter>,
+    dc_block_r: Option<PrimaryFilter>,
+    phase_align_l: Option<BiquadFilter>,
+    phase_align_r: Option<BiquadFilter>,
+    // スピーカーの周波数特性を打ち消し処理の前で補正するEQ
+    input_eq_l: Option<BiquadCascade>,
+    input_eq_r: Option<BiquadCascade>,
+    // 入力でかけるプリエンファシスと、出力で戻すディエンファシス ([左, 右])
+    pre_emphasis: Option<[BiquadFilter; 2]>,
+    de_emphasis: Option<[BiquadFilter; 2]>,
+    // 出力のチルト ([左, 右]) と、中心周波数で利得1にするための補正
+    tilt: Option<[BiquadCascade; 2]>,
+    tilt_gain: f64,
+    limiter: OutputLimiter,
+    ceiling: f64,
+    interpolation: Interpolation,
+    mode: RenderMode,
+    solo: SoloMode,
+    gain_trims: [f64; 2],
+    invert: [bool; 2],
+    clip_counts: [u32; 2],
+    raw_output: bool,
+    frozen: bool,
+    allpass_bypass: bool,
+    hrtf: Option<HrtfEngine>,
+}
+
+// CtcEngine::new に渡す初期値。遅延・カットオフは [左, 右] の経路ごと
+pub struct EngineConfig<'a> {
+    pub sample_rate: f32,
+    pub ct_delays: [f64; 2],
+    pub main_delays: [f64; 2],
+    pub lp_cutoffs: [f32; 2],
+    pub hp_cutoff: f32,
+    pub ls_cutoff: f32,
+    pub ls_gain: f32,
+    pub dc_block: bool,
+    pub allpass_order: usize,
+    pub shadow_stages: [usize; 2],
+    // 指定するとallpass_orderの表の代わりに使う (A系統, B系統)
+    pub custom_poles: Option<(&'a [f64], &'a [f64])>,
 }
 
 impl CtcEngine {
-    pub fn new(
-        sample_rate: f32,
-        ct_delays: [f64; 2],
-        main_delays: [f64; 2],
-        lp_cutoffs: [f32; 2],
-        hp_cutoff: f32,
-        ls_cutoff: f32,
-        ls_gain: f32
-    ) -> Self {
-        let (coeffs_a, coeffs_b) = calc_allpass_coeffs(sample_rate);
-        println!("{:?}", main_delays);
+    pub fn new(config: &EngineConfig) -> Self {
+        let &EngineConfig {
+            sample_rate,
+            ct_delays,
+            main_delays,
+            lp_cutoffs,
+            hp_cutoff,
+            ls_cutoff,
+            ls_gain,
+            dc_block,
+            allpass_order,
+            shadow_stages,
+            custom_poles,
+        } = config;
+        // 独自のポールが与えられたら次数による既定の表より優先する
+        let (poles_a, poles_b) = custom_poles
+            .or_else(|| allpass_poles(allpass_order))
+            .expect("unsupported all-pass order");
+        assert_eq!(poles_a.len(), poles_b.len(), "all-pass pole tables must have the same length");
+        let (coeffs_a, coeffs_b) = calc_allpass_coeffs(sample_rate, poles_a, poles_b);
+        log::debug!("Main delays: {:?}", main_delays);
+        let delays = clamp_delays(ct_delays, main_delays);
+        let delay_ramp_len = (DELAY_RAMP_MS * sample_rate / 1000.0) as usize;
         Self {
+            sample_rate,
+            design: FilterDesign {
+                allpass_poles: (poles_a.to_vec(), poles_b.to_vec()),
+                hp_cutoff,
+                low_shelf: (ls_cutoff, ls_gain),
+                phase_align: [None, None],
+                input_eq: [Vec::new(), Vec::new()],
+                emphasis: None,
+                tilt: 0.0,
+            },
             filter_a_l: coeffs_a.iter().map(|&a| PrimaryFilter::all_pass(a)).collect(),
             filter_a_r: coeffs_a.iter().map(|&a| PrimaryFilter::all_pass(a)).collect(),
             filter_b_l: coeffs_b.iter().map(|&b| PrimaryFilter::all_pass(b)).collect(),
@@ -42,21 +216,327 @@ impl CtcEngine {
             rb_l_0: [0.0; 512],
             rb_r_0: [0.0; 512],
             rb_idx: 0,
-            main_delay_l: main_delays[0],
-            main_delay_r: main_delays[1],
+            main_delay_l: delays[2],
+            main_delay_r: delays[3],
             rb_l_90: [0.0; 512],
             rb_r_90: [0.0; 512],
-            low_pass_l: BiquadFilter::low_pass(sample_rate, lp_cutoffs[0]),
-            low_pass_r: BiquadFilter::low_pass(sample_rate, lp_cutoffs[1]),
-            high_pass_l: BiquadFilter::high_pass(sample_rate, hp_cutoff),
-            high_pass_r: BiquadFilter::high_pass(sample_rate, hp_cutoff),
-            low_shelf_l: BiquadFilter::low_shelf(sample_rate, ls_cutoff, ls_gain),
-            low_shelf_r: BiquadFilter::low_shelf(sample_rate, ls_cutoff, ls_gain),
-            ct_delay_l: ct_delays[0],
-            ct_delay_r: ct_delays[1],
+            low_pass_l: Crossfaded::new(shadow_low_pass(sample_rate, lp_cutoffs[0], shadow_stages[0])),
+            low_pass_r: Crossfaded::new(shadow_low_pass(sample_rate, lp_cutoffs[1], shadow_stages[1])),
+            lp_cutoffs,
+            shadow_stages,
+            extra_stages: Vec::new(),
+            high_pass_l: Crossfaded::new(BiquadFilter::high_pass(sample_rate, hp_cutoff)),
+            high_pass_r: Crossfaded::new(BiquadFilter::high_pass(sample_rate, hp_cutoff)),
+            low_shelf_l: Crossfaded::new(BiquadFilter::low_shelf(sample_rate, ls_cutoff, ls_gain)),
+            low_shelf_r: Crossfaded::new(BiquadFilter::low_shelf(sample_rate, ls_cutoff, ls_gain)),
+            ct_delay_l: delays[0],
+            ct_delay_r: delays[1],
+            delay_smoothers: delays.map(|d| LinearSmoother::new(d, delay_ramp_len)),
+            dc_block_l: dc_block.then(|| PrimaryFilter::dc_block(0.995)),
+            dc_block_r: dc_block.then(|| PrimaryFilter::dc_block(0.995)),
+            phase_align_l: None,
+            phase_align_r: None,
+            limiter: OutputLimiter::default(),
+            ceiling: 1.0,
+            interpolation: Interpolation::default(),
+            mode: RenderMode::default(),
+            gain_trims: [1.0, 1.0],
+            invert: [false, false],
+            clip_counts: [0, 0],
+            raw_output: false,
+            frozen: false,
+            allpass_bypass: false,
+            solo: SoloMode::All,
+            input_eq_l: None,
+            input_eq_r: None,
+            pre_emphasis: None,
+            de_emphasis: None,
+            tilt: None,
+            tilt_gain: 1.0,
+            hrtf: None,
         }
     }
 
+    pub fn set_limiter(&mut self, limiter: OutputLimiter) {
+        self.limiter = limiter;
+    }
+
+    pub fn set_ceiling(&mut self, ceiling: f64) {
+        self.ceiling = ceiling;
+    }
+
+    // 測定用。trueにするとリミッターを通さず、処理結果をそのまま返す
+    pub fn set_raw_output(&mut self, raw_output: bool) {
+        self.raw_output = raw_output;
+    }
+
+    // 測定用。凍結中はフィルタ係数や遅延を変える設定 (遮蔽・シェルフ・位相合わせ・トリム・遅延・強調・傾き) を無視する
+    pub fn freeze(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    // 1で従来どおりの1回の打ち消し。2以上では打ち消し信号自体のクロストークも再帰的に打ち消す
+    pub fn set_cancel_order(&mut self, order: usize) {
+        let (sr, cutoffs, shadow_stages) = (self.sample_rate, self.lp_cutoffs, self.shadow_stages);
+        self.extra_stages = (1..order.max(1)).map(|_| CancelStage::new(sr, cutoffs, shadow_stages)).collect();
+    }
+
+    pub fn reset(&mut self) {
+        for f in self.filter_a_l.iter_mut()
+            .chain(self.filter_a_r.iter_mut())
+            .chain(self.filter_b_l.iter_mut())
+            .chain(self.filter_b_r.iter_mut())
+            .chain(self.dc_block_l.iter_mut())
+            .chain(self.dc_block_r.iter_mut())
+        {
+            f.reset();
+        }
+        self.low_pass_l.reset();
+        self.low_pass_r.reset();
+        for stage in self.extra_stages.iter_mut() {
+            stage.reset();
+        }
+        for f in [
+            &mut self.high_pass_l, &mut self.high_pass_r,
+            &mut self.low_shelf_l, &mut self.low_shelf_r,
+        ] {
+            f.reset();
+        }
+        for f in self.phase_align_l.iter_mut().chain(self.phase_align_r.iter_mut()) {
+            f.reset();
+        }
+        for f in self.input_eq_l.iter_mut().chain(self.input_eq_r.iter_mut()) {
+            f.reset();
+        }
+        for f in self.pre_emphasis.iter_mut().chain(self.de_emphasis.iter_mut()).flatten() {
+            f.reset();
+        }
+        for f in self.tilt.iter_mut().flatten() {
+            f.reset();
+        }
+        self.rb_l_0 = [0.0; 512];
+        self.rb_r_0 = [0.0; 512];
+        self.rb_l_90 = [0.0; 512];
+        self.rb_r_90 = [0.0; 512];
+        self.rb_idx = 0;
+        self.clip_counts = [0, 0];
+        if let Some(hrtf) = self.hrtf.as_mut() {
+            hrtf.reset();
+        }
+    }
+
+    // 左右それぞれの入力にインパルスを与えたときの出力 (2x2の伝達行列)
+    pub fn impulse_response(&mut self, length: usize, attenuation: f64, amp_factors: &[f64; 4]) -> ([Vec<f32>; 2], [Vec<f32>; 2]) {
+        let left = self.record_impulse([1.0, 0.0], length, attenuation, amp_factors);
+        let right = self.record_impulse([0.0, 1.0], length, attenuation, amp_factors);
+        self.reset();
+        (left, right)
+    }
+
+    fn record_impulse(&mut self, impulse: [f32; 2], length: usize, attenuation: f64, amp_factors: &[f64; 4]) -> [Vec<f32>; 2] {
+        self.reset();
+        let mut out = [Vec::with_capacity(length), Vec::with_capacity(length)];
+        for n in 0..length {
+            let input = if n == 0 { impulse } else { [0.0, 0.0] };
+            let [l, r] = self.process(input, attenuation, amp_factors);
+            out[0].push(l);
+            out[1].push(r);
+        }
+        out
+    }
+
+    pub fn set_biquad_targets(
+        &mut self,
+        lp_cutoffs: [f32; 2],
+        hp_cutoff: f32,
+        ls_cutoff: f32,
+        ls_gain: f32,
+        fade_len: usize,
+    ) {
+        if self.frozen { return; }
+        let sr = self.sample_rate;
+        let [stages_l, stages_r] = self.shadow_stages;
+        self.low_pass_l.fade_to(shadow_low_pass(sr, lp_cutoffs[0], stages_l), fade_len);
+        self.low_pass_r.fade_to(shadow_low_pass(sr, lp_cutoffs[1], stages_r), fade_len);
+        for stage in self.extra_stages.iter_mut() {
+            stage.low_pass_l.fade_to(shadow_low_pass(sr, lp_cutoffs[0], stages_l), fade_len);
+            stage.low_pass_r.fade_to(shadow_low_pass(sr, lp_cutoffs[1], stages_r), fade_len);
+        }
+        self.lp_cutoffs = lp_cutoffs;
+        self.high_pass_l.fade_to(BiquadFilter::high_pass(sr, hp_cutoff), fade_len);
+        self.high_pass_r.fade_to(BiquadFilter::high_pass(sr, hp_cutoff), fade_len);
+        self.low_shelf_l.fade_to(BiquadFilter::low_shelf(sr, ls_cutoff, ls_gain), fade_len);
+        self.low_shelf_r.fade_to(BiquadFilter::low_shelf(sr, ls_cutoff, ls_gain), fade_len);
+        self.design.hp_cutoff = hp_cutoff;
+        self.design.low_shelf = (ls_cutoff, ls_gain);
+    }
+
+    // デバイスの切り替えで標本化周波数が変わったときに、作り直さずに係数と遅延を合わせる。
+    // フィルタの状態は捨てるが、遅延線の中身は残して読み出し位置だけを新しいレートに換算する。
+    // HRIRは読み込み時のレートのままなので、HRTFモードでは別に読み直す必要がある
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if sample_rate == self.sample_rate { return; }
+        // 凍結中でも係数と遅延は新しいレートに合わせる
+        let frozen = std::mem::replace(&mut self.frozen, false);
+        let ratio = sample_rate as f64 / self.sample_rate as f64;
+        self.sample_rate = sample_rate;
+        let sr = sample_rate;
+
+        let (poles_a, poles_b) = &self.design.allpass_poles;
+        let (coeffs_a, coeffs_b) = calc_allpass_coeffs(sr, poles_a, poles_b);
+        self.filter_a_l = coeffs_a.iter().map(|&a| PrimaryFilter::all_pass(a)).collect();
+        self.filter_a_r = coeffs_a.iter().map(|&a| PrimaryFilter::all_pass(a)).collect();
+        self.filter_b_l = coeffs_b.iter().map(|&b| PrimaryFilter::all_pass(b)).collect();
+        self.filter_b_r = coeffs_b.iter().map(|&b| PrimaryFilter::all_pass(b)).collect();
+
+        let [stages_l, stages_r] = self.shadow_stages;
+        self.low_pass_l = Crossfaded::new(shadow_low_pass(sr, self.lp_cutoffs[0], stages_l));
+        self.low_pass_r = Crossfaded::new(shadow_low_pass(sr, self.lp_cutoffs[1], stages_r));
+        self.set_cancel_order(self.extra_stages.len() + 1);
+        let hp_cutoff = self.design.hp_cutoff;
+        let (ls_cutoff, ls_gain) = self.design.low_shelf;
+        self.high_pass_l = Crossfaded::new(BiquadFilter::high_pass(sr, hp_cutoff));
+        self.high_pass_r = Crossfaded::new(BiquadFilter::high_pass(sr, hp_cutoff));
+        self.low_shelf_l = Crossfaded::new(BiquadFilter::low_shelf(sr, ls_cutoff, ls_gain));
+        self.low_shelf_r = Crossfaded::new(BiquadFilter::low_shelf(sr, ls_cutoff, ls_gain));
+
+        let [align_l, align_r] = self.design.phase_align;
+        self.set_phase_align(align_l, align_r);
+        let [eq_l, eq_r] = std::mem::take(&mut self.design.input_eq);
+        self.set_input_eq(&eq_l, &eq_r);
+        self.set_emphasis(self.design.emphasis);
+        self.set_tilt(self.design.tilt);
+
+        let current = [self.ct_delay_l, self.ct_delay_r, self.main_delay_l, self.main_delay_r].map(|d| d * ratio);
+        let targets = self.delay_smoothers.each_ref().map(|s| s.target() * ratio);
+        let current = clamp_delays([current[0], current[1]], [current[2], current[3]]);
+        [self.ct_delay_l, self.ct_delay_r, self.main_delay_l, self.main_delay_r] = current;
+        let delay_ramp_len = (DELAY_RAMP_MS * sr / 1000.0) as usize;
+        self.delay_smoothers = current.map(|d| LinearSmoother::new(d, delay_ramp_len));
+        self.set_delay_targets([targets[0], targets[1]], [targets[2], targets[3]]);
+        self.frozen = frozen;
+    }
+
+    // 新しい遅延へはDELAY_RAMP_MSかけて移るので、聴取位置を動かしてもザッピングノイズが出ない
+    pub fn set_delay_targets(&mut self, ct_delays: [f64; 2], main_delays: [f64; 2]) {
+        if self.frozen { return; }
+        for (smoother, d) in self.delay_smoothers.iter_mut().zip(clamp_delays(ct_delays, main_delays)) {
+            smoother.set_target(d);
+        }
+    }
+
+    #[inline(always)]
+    fn advance_delays(&mut self) {
+        let [s_ct_l, s_ct_r, s_main_l, s_main_r] = &mut self.delay_smoothers;
+        self.ct_delay_l = s_ct_l.next_value();
+        self.ct_delay_r = s_ct_r.next_value();
+        self.main_delay_l = s_main_l.next_value();
+        self.main_delay_r = s_main_r.next_value();
+    }
+
+    pub fn set_mode(&mut self, mode: RenderMode) {
+        self.mode = mode;
+    }
+
+    // デバッグ用。trueにすると90°位相差を作るオールパスを通さず、0°側と90°側の両方に入力をそのまま使う
+    pub fn set_allpass_bypass(&mut self, bypass: bool) {
+        self.allpass_bypass = bypass;
+    }
+
+    pub fn set_solo(&mut self, solo: SoloMode) {
+        self.solo = solo;
+    }
+
+    pub fn set_hrtf(&mut self, hrtf: Option<HrtfEngine>) {
+        self.hrtf = hrtf;
+    }
+
+    pub fn set_gain_trims(&mut self, trims_db: [f32; 2]) {
+        if self.frozen { return; }
+        self.gain_trims = trims_db.map(|db| 10.0f64.powf(db as f64 / 20.0));
+    }
+
+    // チャンネル間の群遅延を合わせるためのオールパス (cutoff, Q)。Noneで無効
+    pub fn set_phase_align(&mut self, left: Option<(f32, f64)>, right: Option<(f32, f64)>) {
+        if self.frozen { return; }
+        let sr = self.sample_rate;
+        self.phase_align_l = left.map(|(cutoff, q)| BiquadFilter::all_pass(sr, cutoff, q));
+        self.phase_align_r = right.map(|(cutoff, q)| BiquadFilter::all_pass(sr, cutoff, q));
+        self.design.phase_align = [left, right];
+    }
+
+    // ピーキングEQの帯域 (周波数, 利得dB, Q) をチャンネルごとに指定する。空なら無効
+    pub fn set_input_eq(&mut self, left: &[(f32, f32, f64)], right: &[(f32, f32, f64)]) {
+        if self.frozen { return; }
+        let sr = self.sample_rate;
+        let build = |bands: &[(f32, f32, f64)]| (!bands.is_empty()).then(|| {
+            BiquadCascade::new(bands.iter().map(|&(freq, gain, q)| BiquadFilter::peaking(sr, freq, gain, q)))
+        });
+        self.input_eq_l = build(left);
+        self.input_eq_r = build(right);
+        self.design.input_eq = [left.to_vec(), right.to_vec()];
+    }
+
+    // 高域をgain_db持ち上げて処理し、出力で同じだけ下げる。Noneで無効
+    pub fn set_emphasis(&mut self, gain_db: Option<f32>) {
+        if self.frozen { return; }
+        let sr = self.sample_rate;
+        let cutoff = EMPHASIS_CUTOFF_HZ.min(sr / 4.0);
+        self.pre_emphasis = gain_db.map(|g| [BiquadFilter::high_shelf(sr, cutoff, g), BiquadFilter::high_shelf(sr, cutoff, g)]);
+        self.de_emphasis = gain_db.map(|g| [BiquadFilter::high_shelf(sr, cutoff, -g), BiquadFilter::high_shelf(sr, cutoff, -g)]);
+        self.design.emphasis = gain_db;
+    }
+
+    // 1オクターブごとのハイシェルフを中心周波数の上下対称に並べ、オクターブあたりdb_per_octaveの傾きを作る。
+    // シェルフのdB特性はカットオフを中心に点対称なので、1組あたり中心周波数でちょうどdb_per_octaveだけ持ち上がる
+    pub fn set_tilt(&mut self, db_per_octave: f32) {
+        if self.frozen { return; }
+        self.design.tilt = db_per_octave;
+        if db_per_octave == 0.0 {
+            self.tilt = None;
+            self.tilt_gain = 1.0;
+            return;
+        }
+        let sr = self.sample_rate;
+        let pairs = (1..=TILT_SHELF_PAIRS)
+            .take_while(|&k| TILT_PIVOT_HZ * 2.0f32.powf(k as f32 - 0.5) < sr * 0.45)
+            .count() as i32;
+        let build = || BiquadCascade::new((-pairs..pairs).map(|k| {
+            BiquadFilter::high_shelf(sr, TILT_PIVOT_HZ * 2.0f32.powf(k as f32 + 0.5), db_per_octave)
+        }));
+        self.tilt = Some([build(), build()]);
+        self.tilt_gain = 10.0f64.powf(-(pairs as f64) * db_per_octave as f64 / 20.0);
+    }
+
+    pub fn set_invert(&mut self, invert: [bool; 2]) {
+        self.invert = invert;
+    }
+
+    pub fn coefficients(&self) -> EngineCoefficients {
+        EngineCoefficients {
+            low_pass: [self.low_pass_l.target().coefficients(), self.low_pass_r.target().coefficients()],
+            high_pass: [self.high_pass_l.target().coefficients(), self.high_pass_r.target().coefficients()],
+            low_shelf: [self.low_shelf_l.target().coefficients(), self.low_shelf_r.target().coefficients()],
+            allpass_a: self.filter_a_l.iter().map(|f| f.alpha()).collect(),
+            allpass_b: self.filter_b_l.iter().map(|f| f.alpha()).collect(),
+            frozen: self.is_frozen(),
+        }
+    }
+
+    // 前回の呼び出し以降にceilingを超えたサンプル数 (チャンネル別)
+    pub fn take_clip_counts(&mut self) -> [u32; 2] {
+        std::mem::take(&mut self.clip_counts)
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    // delayはMIN_*_DELAY..=MAX_DELAYに収まっている前提 (newで制限している)
     #[inline(always)]
     fn get_interpolated(&self, buffer: &[f64], current_idx: usize, delay: f64) -> f64 {
         let read_pos = current_idx as f64 - delay;
@@ -67,6 +547,19 @@ impl CtcEngine {
 
         let frac = read_pos- pos_floor;
 
+        // Catmull-Romはidx_bの一つ先も読むため、遅延が短いと未書き込みの位置を参照してしまう
+        if matches!(self.interpolation, Interpolation::Cubic) && delay >= 3.0 {
+            let idx_0 = (idx_a + 511) & 511;
+            let idx_3 = (idx_a + 2) & 511;
+            return unsafe {
+                let p0 = *buffer.get_unchecked(idx_0);
+                let p1 = *buffer.get_unchecked(idx_a);
+                let p2 = *buffer.get_unchecked(idx_b);
+                let p3 = *buffer.get_unchecked(idx_3);
+                p1 + 0.5 * frac * (p2 - p0 + frac * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + frac * (3.0 * (p1 - p2) + p3 - p0)))
+            };
+        }
+
         unsafe {
             let val_a = *buffer.get_unchecked(idx_a);
             let val_b = *buffer.get_unchecked(idx_b);
@@ -74,14 +567,38 @@ impl CtcEngine {
         }
     }
     
-    pub fn process(&mut self, [l, r]: [f32; 2], attenuation: f64, amp_factors: &[f64; 4]) -> [f32; 2] {
+    pub fn process(&mut self, [mut l, mut r]: [f32; 2], attenuation: f64, amp_factors: &[f64; 4]) -> [f32; 2] {
+        // ドライバの不具合で来る非有限値は無音として扱い、フィルタや遅延線に入れない
+        if !l.is_finite() { l = 0.0; }
+        if !r.is_finite() { r = 0.0; }
+        // 逆相に配線されたスピーカーを入力側で補正する
+        if self.invert[0] { l = -l; }
+        if self.invert[1] { r = -r; }
+        if let Some(eq) = self.input_eq_l.as_mut() { l = eq.process(l as f64) as f32; }
+        if let Some(eq) = self.input_eq_r.as_mut() { r = eq.process(r as f64) as f32; }
+        if let Some([f_l, f_r]) = self.pre_emphasis.as_mut() {
+            l = f_l.process(l as f64) as f32;
+            r = f_r.process(r as f64) as f32;
+        }
+        self.advance_delays();
+        if let RenderMode::HeadphoneCrossfeed = self.mode {
+            return self.process_crossfeed([l, r], attenuation);
+        }
+        if let (RenderMode::Hrtf, Some(hrtf)) = (self.mode, self.hrtf.as_mut()) {
+            let out = hrtf.process([l as f64, r as f64]);
+            return self.finish(out);
+        }
+
         let fold_fn = |acc: f64, f: &mut PrimaryFilter| f.process(acc);
 
         let l_in = l as f64;
         let r_in = r as f64;
 
-        let l_0 = self.filter_a_l.iter_mut().fold(l_in, fold_fn);
-        let r_0 = self.filter_a_r.iter_mut().fold(r_in, fold_fn);
+        let (l_0, r_0) = if self.allpass_bypass {
+            (l_in, r_in)
+        } else {
+            (self.filter_a_l.iter_mut().fold(l_in, fold_fn), self.filter_a_r.iter_mut().fold(r_in, fold_fn))
+        };
 
         let ct_l_90_delayed = self.get_interpolated(&self.rb_l_90, self.rb_idx, self.ct_delay_l);
         let ct_r_90_delayed = self.get_interpolated(&self.rb_r_90, self.rb_idx, self.ct_delay_r);
@@ -89,8 +606,29 @@ impl CtcEngine {
         let ct_l_90 = self.low_pass_l.process(ct_l_90_delayed);
         let ct_r_90 = self.low_pass_r.process(ct_r_90_delayed);
 
-        let res_l = l_0 * amp_factors[0] - ct_r_90 * attenuation * amp_factors[2];
-        let res_r = r_0 * amp_factors[3] - ct_l_90 * attenuation * amp_factors[1];
+        // 左スピーカー: 直接音は左耳への経路、打ち消しは右スピーカーから左耳へのクロストーク経路で重み付けする
+        let mut cancel_l = -ct_r_90 * attenuation * amp_factors[RS_TO_LE];
+        let mut cancel_r = -ct_l_90 * attenuation * amp_factors[LS_TO_RE];
+        let direct = [l_0 * amp_factors[LS_TO_LE], r_0 * amp_factors[RS_TO_RE]];
+        let mut cancel_sum = [cancel_l, cancel_r];
+
+        // 前段で反対側のスピーカーが出した打ち消し信号も、同じ経路で漏れてくるので打ち消す
+        for i in 0..self.extra_stages.len() {
+            let leak_r = self.get_interpolated(&self.extra_stages[i].rb_r, self.rb_idx, self.ct_delay_r);
+            let leak_l = self.get_interpolated(&self.extra_stages[i].rb_l, self.rb_idx, self.ct_delay_l);
+            let stage = &mut self.extra_stages[i];
+            stage.rb_l[self.rb_idx] = cancel_l;
+            stage.rb_r[self.rb_idx] = cancel_r;
+            cancel_l = -stage.low_pass_r.process(leak_r) * attenuation * amp_factors[RS_TO_LE];
+            cancel_r = -stage.low_pass_l.process(leak_l) * attenuation * amp_factors[LS_TO_RE];
+            cancel_sum[0] += cancel_l;
+            cancel_sum[1] += cancel_r;
+        }
+        let [res_l, res_r] = match self.solo {
+            SoloMode::All => [direct[0] + cancel_sum[0], direct[1] + cancel_sum[1]],
+            SoloMode::DirectOnly => direct,
+            SoloMode::CrosstalkOnly => cancel_sum,
+        };
 
         self.rb_l_0[self.rb_idx] = self.low_shelf_l.process(res_l);
         self.rb_r_0[self.rb_idx] = self.low_shelf_r.process(res_r);
@@ -98,28 +636,724 @@ impl CtcEngine {
         let out_l = self.get_interpolated(&self.rb_l_0, self.rb_idx, self.main_delay_l);
         let out_r = self.get_interpolated(&self.rb_r_0, self.rb_idx, self.main_delay_r);
 
-        let fb_l_90 = self.filter_b_l.iter_mut().fold(l_in, fold_fn); // res_lは再帰型
-        let fb_r_90 = self.filter_b_r.iter_mut().fold(r_in, fold_fn); // res_rは再帰型
+        let (fb_l_90, fb_r_90) = if self.allpass_bypass {
+            (l_in, r_in)
+        } else {
+            // res_l, res_rは再帰型
+            (self.filter_b_l.iter_mut().fold(l_in, fold_fn), self.filter_b_r.iter_mut().fold(r_in, fold_fn))
+        };
         self.rb_l_90[self.rb_idx] = self.high_pass_l.process(fb_l_90);
         self.rb_r_90[self.rb_idx] = self.high_pass_r.process(fb_r_90);
 
         self.rb_idx = (self.rb_idx + 1) % 512;
 
-        [ out_l.clamp(-1.0, 1.0) as f32, out_r.clamp(-1.0, 1.0) as f32 ]
+        self.finish([out_l, out_r])
+    }
+
+    // inputをまとめて処理してoutputに書き込む (長さは短い方に合わせる)
+    pub fn process_block(&mut self, input: &[[f32; 2]], output: &mut [[f32; 2]], attenuation: f64, amp_factors: &[f64; 4]) {
+        for (&x, y) in input.iter().zip(output.iter_mut()) {
+            *y = self.process(x, attenuation, amp_factors);
+        }
+    }
+
+    fn process_crossfeed(&mut self, [l, r]: [f32; 2], attenuation: f64) -> [f32; 2] {
+        let l_in = l as f64;
+        let r_in = r as f64;
+
+        let cf_l_delayed = self.get_interpolated(&self.rb_l_90, self.rb_idx, self.ct_delay_l);
+        let cf_r_delayed = self.get_interpolated(&self.rb_r_90, self.rb_idx, self.ct_delay_r);
+
+        let cf_l = self.low_pass_l.process(cf_l_delayed);
+        let cf_r = self.low_pass_r.process(cf_r_delayed);
+
+        self.rb_l_90[self.rb_idx] = l_in;
+        self.rb_r_90[self.rb_idx] = r_in;
+
+        self.rb_idx = (self.rb_idx + 1) % 512;
+
+        // 中央定位の音量が変わらないように正規化
+        let norm = 1.0 / (1.0 + attenuation.abs());
+        self.finish([ (l_in + cf_r * attenuation) * norm, (r_in + cf_l * attenuation) * norm ])
+    }
+
+    #[inline(always)]
+    fn finish(&mut self, [mut out_l, mut out_r]: [f64; 2]) -> [f32; 2] {
+        if let Some([f_l, f_r]) = self.de_emphasis.as_mut() {
+            out_l = f_l.process(out_l);
+            out_r = f_r.process(out_r);
+        }
+        if let Some([f_l, f_r]) = self.tilt.as_mut() {
+            out_l = f_l.process(out_l) * self.tilt_gain;
+            out_r = f_r.process(out_r) * self.tilt_gain;
+        }
+        if let Some(f) = self.dc_block_l.as_mut() { out_l = f.process(out_l); }
+        if let Some(f) = self.dc_block_r.as_mut() { out_r = f.process(out_r); }
+        if let Some(f) = self.phase_align_l.as_mut() { out_l = f.process(out_l); }
+        if let Some(f) = self.phase_align_r.as_mut() { out_r = f.process(out_r); }
+        // それでも内部状態が発散したら、状態を捨てて無音から立て直す
+        if !out_l.is_finite() || !out_r.is_finite() {
+            let clip_counts = self.clip_counts;
+            self.reset();
+            self.clip_counts = clip_counts;
+            return [0.0, 0.0];
+        }
+
+        self.clip_counts[0] += (out_l.abs() > self.ceiling) as u32;
+        self.clip_counts[1] += (out_r.abs() > self.ceiling) as u32;
+        // トリムはリミッターの後の最終出力に掛ける (+6 dBで約2倍、10^(6/20) ≈ 1.995)。
+        // 正のトリムでceilingを超えないよう、掛けた後にもう一度ceilingで抑える
+        if self.raw_output {
+            return [(out_l * self.gain_trims[0]) as f32, (out_r * self.gain_trims[1]) as f32];
+        }
+        let ceiling = self.ceiling;
+        let out_l = (self.limiter.apply(out_l, ceiling) * self.gain_trims[0]).clamp(-ceiling, ceiling);
+        let out_r = (self.limiter.apply(out_r, ceiling) * self.gain_trims[1]).clamp(-ceiling, ceiling);
+        [out_l as f32, out_r as f32]
     }
 }
 
-fn calc_allpass_coeffs(sample_rate: f32) -> (Vec<f32>, Vec<f32>) {
-    let poles_a = [ 1.252477174013740, 5.567151121010343, 22.33405370220630, 121.1823101311035 ];
-    let poles_b = [ 0.470942544153024, 2.511195608677685, 9.736028549641775, 52.32115162453549 ];
+// [ct_l, ct_r, main_l, main_r]の順で遅延線から読める範囲に収める
+fn clamp_delays(ct_delays: [f64; 2], main_delays: [f64; 2]) -> [f64; 4] {
+    [
+        ct_delays[0].clamp(MIN_CT_DELAY, MAX_DELAY),
+        ct_delays[1].clamp(MIN_CT_DELAY, MAX_DELAY),
+        main_delays[0].clamp(MIN_MAIN_DELAY, MAX_DELAY),
+        main_delays[1].clamp(MIN_MAIN_DELAY, MAX_DELAY),
+    ]
+}
+
+struct CancelStage {
+    rb_l: [f64; 512],
+    rb_r: [f64; 512],
+    low_pass_l: Crossfaded<BiquadCascade>,
+    low_pass_r: Crossfaded<BiquadCascade>,
+}
+
+impl CancelStage {
+    fn new(sample_rate: f32, lp_cutoffs: [f32; 2], shadow_stages: [usize; 2]) -> Self {
+        Self {
+            rb_l: [0.0; 512],
+            rb_r: [0.0; 512],
+            low_pass_l: Crossfaded::new(shadow_low_pass(sample_rate, lp_cutoffs[0], shadow_stages[0])),
+            low_pass_r: Crossfaded::new(shadow_low_pass(sample_rate, lp_cutoffs[1], shadow_stages[1])),
+        }
+    }
 
+    fn reset(&mut self) {
+        self.rb_l = [0.0; 512];
+        self.rb_r = [0.0; 512];
+        self.low_pass_l.reset();
+        self.low_pass_r.reset();
+    }
+}
+
+// オールパス回路のポール (150Hz単位)。A系列は直接音、B系列は打ち消し信号に掛かる。
+// どの次数も1kHzを中心に幾何対称な等リプルの90°移相回路 (Darlington, "Realization of a constant
+// phase difference", Bell Syst. Tech. J. 29, 1950) で、アナログ原型の100Hz〜10kHzでA-B間の位相差が
+// 90°から外れる幅は2次で±8.5°、4次で±0.32°、6次で±0.012°。双一次変換で高域側が縮むので、48kHzでは約8.8kHzまでになる
+const ALLPASS_POLES_2: ([f64; 2], [f64; 2]) = (
+    [ 3.118979250200, 81.20513305105 ],
+    [ 0.547310776728, 14.24967621750 ],
+);
+const ALLPASS_POLES_4: ([f64; 4], [f64; 4]) = (
+    [ 0.916767221493, 4.565540770546, 20.99277324880, 173.9331473913 ],
+    [ 0.255526017387, 2.117130686722, 9.734760169303, 48.47953046581 ],
+);
+const ALLPASS_POLES_6: ([f64; 6], [f64; 6]) = (
+    [ 0.547310735799, 1.855753655553, 5.180325005257, 14.24967699733, 41.70065976127, 264.2868914295 ],
+    [ 0.168167419141, 1.065797152823, 3.118979079507, 8.579470284065, 23.94953894414, 81.20513912366 ],
+);
+
+pub fn shadow_low_pass(sample_rate: f32, cutoff: f32, stages: usize) -> BiquadCascade {
+    BiquadCascade::butterworth_low_pass(sample_rate, cutoff, 2 * stages)
+}
+
+pub fn allpass_poles(order: usize) -> Option<(&'static [f64], &'static [f64])> {
+    match order {
+        2 => Some((&ALLPASS_POLES_2.0, &ALLPASS_POLES_2.1)),
+        4 => Some((&ALLPASS_POLES_4.0, &ALLPASS_POLES_4.1)),
+        6 => Some((&ALLPASS_POLES_6.0, &ALLPASS_POLES_6.1)),
+        _ => None,
+    }
+}
+
+fn calc_allpass_coeffs(sample_rate: f32, poles_a: &[f64], poles_b: &[f64]) -> (Vec<f32>, Vec<f32>) {
+    // アナログのオールパス (p - s) / (p + s) を双一次変換 (s = 2fs (1 - z^-1) / (1 + z^-1)) した係数。
+    // プリワープしないので、ナイキスト周波数より上のポールでも安定なまま使える
     let calc = |p: f64| {
-        let omega = 2.0 * std::f64::consts::PI * (p as f64) * 150.0 / sample_rate as f64;
-        (1.0 - omega) / (1.0 + omega)
+        let omega = std::f64::consts::PI * p * 150.0 / sample_rate as f64;
+        (omega - 1.0) / (omega + 1.0)
     };
 
     (
         poles_a.iter().map(|&p| calc(p) as f32).collect(),
         poles_b.iter().map(|&p| calc(p) as f32).collect()
     )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 48000.0;
+    const UNITY: [f64; 4] = [1.0, 0.0, 0.0, 1.0];
+
+    // 遅延10サンプル・遮蔽2kHzの左右対称なエンジンの設定
+    fn config() -> EngineConfig<'static> {
+        EngineConfig {
+            sample_rate: SR,
+            ct_delays: [10.0, 10.0],
+            main_delays: [0.0, 0.0],
+            lp_cutoffs: [2000.0, 2000.0],
+            hp_cutoff: 20.0,
+            ls_cutoff: 200.0,
+            ls_gain: 0.0,
+            dc_block: false,
+            allpass_order: 4,
+            shadow_stages: [1, 1],
+            custom_poles: None,
+        }
+    }
+
+    fn engine(dc_block: bool) -> CtcEngine {
+        CtcEngine::new(&EngineConfig { dc_block, ..config() })
+    }
+
+    fn run(engine: &mut CtcEngine, input: impl Iterator<Item = [f32; 2]>, amp_factors: &[f64; 4]) -> Vec<[f32; 2]> {
+        input.map(|x| engine.process(x, 0.7, amp_factors)).collect()
+    }
+
+    #[test]
+    fn limiter_transfer_curves() {
+        let curve = |limiter: OutputLimiter| [0.5, 1.0, 2.0].map(|x| limiter.apply(x, 1.0));
+        let expected = [
+            (OutputLimiter::Hard, [0.5, 1.0, 1.0]),
+            (OutputLimiter::Tanh, [0.5f64.tanh(), 1.0f64.tanh(), 2.0f64.tanh()]),
+            (OutputLimiter::Cubic, [0.6875, 1.0, 1.0]),
+        ];
+        for (limiter, expected) in expected {
+            for (y, e) in curve(limiter).into_iter().zip(expected) {
+                assert!((y - e).abs() < 1e-12, "{} != {}", y, e);
+            }
+        }
+        // ソフトクリップは1.0未満の入力でもすでに丸め始め、負の入力には奇対称に働く
+        assert!(OutputLimiter::Tanh.apply(0.5, 1.0) < 0.5);
+        assert_eq!(OutputLimiter::Cubic.apply(-2.0, 1.0), -1.0);
+    }
+
+    #[test]
+    fn dc_block_removes_constant_offset() {
+        let input = || std::iter::repeat_n([0.5f32, 0.5], SR as usize);
+        let mean = |out: &[[f32; 2]]| out.iter().map(|f| f[0] as f64).sum::<f64>() / out.len() as f64;
+        let blocked = run(&mut engine(true), input(), &UNITY);
+        let passed = run(&mut engine(false), input(), &UNITY);
+        let tail = SR as usize - 1000;
+        assert!(mean(&blocked[tail..]).abs() < 1e-3, "{}", mean(&blocked[tail..]));
+        assert!(mean(&passed[tail..]).abs() > 0.1);
+    }
+
+    #[test]
+    fn cubic_interpolation_beats_linear_at_high_frequencies() {
+        // 遅延線に正弦波を書き込み、小数遅延で読んだ値を真値と比べる
+        let max_error = |interpolation: Interpolation, cycles_per_sample: f64| {
+            let mut e = engine(false);
+            e.set_interpolation(interpolation);
+            let w = 2.0 * std::f64::consts::PI * cycles_per_sample;
+            let buffer: [f64; 512] = std::array::from_fn(|n| (w * n as f64).sin());
+            let delay = 10.37;
+            (20..500).map(|idx| (e.get_interpolated(&buffer, idx, delay) - (w * (idx as f64 - delay)).sin()).abs())
+                .fold(0.0, f64::max)
+        };
+        for cycles_per_sample in [0.1, 0.2, 0.3, 0.35] {
+            let linear = max_error(Interpolation::Linear, cycles_per_sample);
+            let cubic = max_error(Interpolation::Cubic, cycles_per_sample);
+            assert!(cubic < linear, "{}: cubic {} vs linear {}", cycles_per_sample, cubic, linear);
+        }
+    }
+
+    #[test]
+    fn crossfeed_keeps_center_level() {
+        let mut e = engine(false);
+        e.set_mode(RenderMode::HeadphoneCrossfeed);
+        let out = run(&mut e, std::iter::repeat_n([0.5f32, 0.5], 4800), &UNITY);
+        let [l, r] = out[out.len() - 1];
+        assert!((l - 0.5).abs() < 1e-3 && (r - 0.5).abs() < 1e-3, "{} {}", l, r);
+        // 片側だけの入力は反対側に減衰して漏れる
+        e.reset();
+        let out = run(&mut e, std::iter::repeat_n([0.5f32, 0.0], 4800), &UNITY);
+        let [l, r] = out[out.len() - 1];
+        assert!(r > 0.0 && r < l);
+    }
+
+    #[test]
+    fn six_db_trim_doubles_the_channel() {
+        let input = || (0..480).map(|n| {
+            let x = (n as f32 * 0.05).sin() * 0.2;
+            [x, x]
+        });
+        let plain = run(&mut engine(false), input(), &UNITY);
+        let mut trimmed = engine(false);
+        trimmed.set_gain_trims([6.0, 0.0]);
+        let trimmed = run(&mut trimmed, input(), &UNITY);
+        for (p, t) in plain.iter().zip(&trimmed) {
+            assert!((t[0] - p[0] * 2.0).abs() < 0.005 * p[0].abs().max(1e-3), "{} vs {}", t[0], p[0]);
+            assert_eq!(t[1], p[1]);
+        }
+    }
+
+    #[test]
+    fn positive_trim_stays_under_the_ceiling() {
+        let mut e = engine(false);
+        e.set_ceiling(0.708);
+        e.set_gain_trims([6.0, 6.0]);
+        let input = (0..4800).map(|n| {
+            let x = 3.0 * (2.0 * std::f32::consts::PI * 440.0 * n as f32 / SR).sin();
+            [x, -x]
+        });
+        let peak = run(&mut e, input, &[1.0; 4]).iter().flatten().fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!(peak <= 0.708, "{}", peak);
+    }
+
+    // A系列とB系列の応答の位相差 (度)。PrimaryFilter::all_passは H(z) = (a + z^-1) / (1 + a z^-1)
+    fn phase_difference_deg(order: usize, freq: f64) -> f64 {
+        let (poles_a, poles_b) = allpass_poles(order).unwrap();
+        let (coeffs_a, coeffs_b) = calc_allpass_coeffs(SR, poles_a, poles_b);
+        let w = 2.0 * std::f64::consts::PI * freq / SR as f64;
+        let phase = |coeffs: &[f32]| -> f64 {
+            coeffs.iter().map(|&a| {
+                let a = a as f64;
+                (-w.sin()).atan2(a + w.cos()) - (-a * w.sin()).atan2(1.0 + a * w.cos())
+            }).sum()
+        };
+        let deg = (phase(&coeffs_a) - phase(&coeffs_b)).to_degrees();
+        (deg + 180.0).rem_euclid(360.0) - 180.0
+    }
+
+    // 実際のフィルタ列に正弦波を通して測ったA出力とB出力の位相差 (度)
+    fn measured_phase_difference_deg(order: usize, freq: f64) -> f64 {
+        let (poles_a, poles_b) = allpass_poles(order).unwrap();
+        let (coeffs_a, coeffs_b) = calc_allpass_coeffs(SR, poles_a, poles_b);
+        let chain = |coeffs: &[f32]| -> Vec<PrimaryFilter> { coeffs.iter().map(|&a| PrimaryFilter::all_pass(a)).collect() };
+        let (mut chain_a, mut chain_b) = (chain(&coeffs_a), chain(&coeffs_b));
+        let w = 2.0 * std::f64::consts::PI * freq / SR as f64;
+        let (mut a, mut b) = ((0.0, 0.0), (0.0, 0.0));
+        for n in 0..SR as usize {
+            let x = (w * n as f64).sin();
+            let y_a = chain_a.iter_mut().fold(x, |acc, f| f.process(acc));
+            let y_b = chain_b.iter_mut().fold(x, |acc, f| f.process(acc));
+            // 過渡応答が収まった後半だけを複素振幅に射影する
+            if n >= SR as usize / 2 {
+                let (s, c) = (w * n as f64).sin_cos();
+                a = (a.0 + y_a * s, a.1 + y_a * c);
+                b = (b.0 + y_b * s, b.1 + y_b * c);
+            }
+        }
+        let deg = (a.1.atan2(a.0) - b.1.atan2(b.0)).to_degrees();
+        (deg + 180.0).rem_euclid(360.0) - 180.0
+    }
+
+    #[test]
+    fn allpass_orders_keep_a_and_b_in_quadrature() {
+        // アナログ原型の100Hz〜10kHzは、48kHzでの双一次変換で100Hz〜約8.8kHzになる
+        let freqs: Vec<f64> = (0..=60).map(|i| 100.0 * 88f64.powf(i as f64 / 60.0)).collect();
+        let mut max_errors = Vec::new();
+        for (order, ripple) in [(2, 8.5), (4, 0.32), (6, 0.012)] {
+            let max_error = freqs.iter().map(|&f| (phase_difference_deg(order, f) - 90.0).abs()).fold(0.0, f64::max);
+            assert!(max_error < ripple * 1.05, "order {}: {} deg", order, max_error);
+            for freq in [300.0, 1000.0, 3000.0] {
+                let expected = phase_difference_deg(order, freq);
+                let measured = measured_phase_difference_deg(order, freq);
+                assert!((measured - expected).abs() < 0.5, "order {} at {} Hz: {} != {}", order, freq, measured, expected);
+            }
+            max_errors.push(max_error);
+        }
+        // セクションを増やすほど90°からのずれは小さくなる
+        assert!(max_errors[0] > max_errors[1] && max_errors[1] > max_errors[2], "{:?}", max_errors);
+    }
+
+    #[test]
+    fn impulse_response_starts_at_the_path_delays() {
+        let mut engine = CtcEngine::new(&EngineConfig { main_delays: [5.0, 5.0], ..config() });
+        let (left, right) = engine.impulse_response(64, 0.7, &[1.0; 4]);
+        let first_nonzero = |ir: &[f32]| ir.iter().position(|&x| x != 0.0);
+        // 同じ側は主遅延だけ、反対側は打ち消し経路の遅延がさらに加わる
+        assert_eq!(first_nonzero(&left[0]), Some(5));
+        assert_eq!(first_nonzero(&left[1]), Some(15));
+        assert_eq!(first_nonzero(&right[1]), Some(5));
+        assert_eq!(first_nonzero(&right[0]), Some(15));
+    }
+
+    #[test]
+    fn each_amp_factor_scales_its_own_path() {
+        let mut factors = [0.0; 4];
+        factors[LS_TO_LE] = 0.9;
+        factors[LS_TO_RE] = 0.3;
+        factors[RS_TO_LE] = 0.6;
+        factors[RS_TO_RE] = 1.2;
+        let (left, right) = engine(false).impulse_response(32, 0.7, &factors);
+        let (left_ref, right_ref) = engine(false).impulse_response(32, 0.7, &[1.0; 4]);
+        // (出力, 基準, 期待する比)。直接音はn=0、打ち消しは遅延10サンプル後に最初に現れる
+        let paths = [
+            (left[0][0], left_ref[0][0], factors[LS_TO_LE]),
+            (left[1][10], left_ref[1][10], factors[LS_TO_RE]),
+            (right[0][10], right_ref[0][10], factors[RS_TO_LE]),
+            (right[1][0], right_ref[1][0], factors[RS_TO_RE]),
+        ];
+        for (i, (y, y_ref, factor)) in paths.into_iter().enumerate() {
+            assert!(y_ref != 0.0, "path {}", i);
+            assert!((y / y_ref - factor as f32).abs() < 1e-5, "path {}: {} / {} != {}", i, y, y_ref, factor);
+        }
+    }
+
+    #[test]
+    fn output_stays_under_the_ceiling() {
+        let ceiling = 0.708; // -3 dBFS
+        for (name, limiter) in [("hard", OutputLimiter::Hard), ("tanh", OutputLimiter::Tanh), ("cubic", OutputLimiter::Cubic)] {
+            let mut e = engine(false);
+            e.set_limiter(limiter);
+            e.set_ceiling(ceiling);
+            let input = (0..4800).map(|n| {
+                let x = 3.0 * (2.0 * std::f32::consts::PI * 440.0 * n as f32 / SR).sin();
+                [x, -x]
+            });
+            let out = run(&mut e, input, &[1.0; 4]);
+            let peak = out.iter().flatten().fold(0.0f32, |m, x| m.max(x.abs()));
+            assert!(peak <= ceiling as f32, "{}: {}", name, peak);
+            // 振幅3の入力なら天井近くまで使う
+            assert!(peak as f64 > ceiling * 0.9, "{}: {}", name, peak);
+        }
+    }
+
+    #[test]
+    fn inverting_left_flips_its_contribution() {
+        let input = || (0..2000).map(|n| [(n as f32 * 0.05).sin() * 0.5, 0.0]);
+        let normal = run(&mut engine(false), input(), &[1.0; 4]);
+        let mut inverted_engine = engine(false);
+        inverted_engine.set_invert([true, false]);
+        let inverted = run(&mut inverted_engine, input(), &[1.0; 4]);
+        assert!(normal.iter().any(|f| f[0].abs() > 0.1 && f[1].abs() > 0.01));
+        // 左の入力は直接音と打ち消しの両方で符号が反転する
+        for (a, b) in normal.iter().zip(&inverted) {
+            assert_eq!([-a[0], -a[1]], *b);
+        }
+    }
+
+    // 左にインパルスを入れ、エンジンと同じ遅延・減衰・遮蔽で耳に届く音を合成する。
+    // (右耳に残るエネルギー, 左耳の直接音からのずれのエネルギー) をdBで返す
+    fn ear_residuals(order: usize) -> (f64, f64) {
+        let len = 4096;
+        let impulse = || (0..len).map(|n| [if n == 0 { 1.0 } else { 0.0 }, 0.0]);
+        let mut e = engine(false);
+        e.set_cancel_order(order);
+        let out = run(&mut e, impulse(), &[1.0; 4]);
+        let mut direct_only = engine(false);
+        direct_only.set_solo(SoloMode::DirectOnly);
+        let direct = run(&mut direct_only, impulse(), &[1.0; 4]);
+        let mut shadow = [BiquadFilter::low_pass(SR, 2000.0), BiquadFilter::low_pass(SR, 2000.0)];
+        let (mut contra, mut ipsi) = (0.0, 0.0);
+        for n in 0..len {
+            let leak = if n >= 10 { out[n - 10] } else { [0.0; 2] };
+            let ear_l = out[n][0] as f64 + 0.7 * shadow[0].process(leak[1] as f64);
+            let ear_r = out[n][1] as f64 + 0.7 * shadow[1].process(leak[0] as f64);
+            contra += ear_r * ear_r;
+            ipsi += (ear_l - direct[n][0] as f64).powi(2);
+        }
+        (10.0 * contra.log10(), 10.0 * ipsi.log10())
+    }
+
+    #[test]
+    fn second_order_cancels_the_cancellation_leak() {
+        let (contra_1, ipsi_1) = ear_residuals(1);
+        let (contra_2, ipsi_2) = ear_residuals(2);
+        // 右スピーカーの打ち消し信号が左耳に漏れる分を、2次では左スピーカーが打ち消す
+        assert!(ipsi_2 < ipsi_1 - 40.0, "{} vs {}", ipsi_2, ipsi_1);
+        // 反対側の耳の残差はA/B間の90°の位相差で決まり、次数ではほとんど変わらないので、合計での改善は小さい
+        let total = |a: f64, b: f64| 10.0 * (10f64.powf(a / 10.0) + 10f64.powf(b / 10.0)).log10();
+        assert!(total(contra_2, ipsi_2) < total(contra_1, ipsi_1),
+            "order 2: {} dB, order 1: {} dB", total(contra_2, ipsi_2), total(contra_1, ipsi_1));
+    }
+
+    #[test]
+    fn overdriven_input_is_counted_as_clipping() {
+        let mut e = engine(false);
+        let quiet = (0..480).map(|n| [(n as f32 * 0.1).sin() * 0.1, 0.0]);
+        run(&mut e, quiet, &UNITY);
+        assert_eq!(e.take_clip_counts(), [0, 0]);
+        let loud = (0..480).map(|n| [(n as f32 * 0.1).sin() * 4.0, 0.0]);
+        run(&mut e, loud, &UNITY);
+        let [left, right] = e.take_clip_counts();
+        assert!(left > 100, "{}", left);
+        assert_eq!(right, 0);
+        // 読み出すと0に戻る
+        assert_eq!(e.take_clip_counts(), [0, 0]);
+    }
+
+    #[test]
+    fn each_crosstalk_path_uses_its_own_cutoff() {
+        // 6kHzの正弦波を片側だけに入れ、反対側に出る打ち消し信号のRMSを測る
+        let leak_rms = |lp_cutoffs: [f32; 2], from_left: bool| {
+            let mut e = CtcEngine::new(&EngineConfig { lp_cutoffs, ..config() });
+            e.set_solo(SoloMode::CrosstalkOnly);
+            let w = 2.0 * std::f32::consts::PI * 6000.0 / SR;
+            let input = (0..4800).map(|n| {
+                let x = (w * n as f32).sin() * 0.5;
+                if from_left { [x, 0.0] } else { [0.0, x] }
+            });
+            let out = run(&mut e, input, &[1.0; 4]);
+            let ch = if from_left { 1 } else { 0 };
+            (out[2400..].iter().map(|f| (f[ch] as f64).powi(2)).sum::<f64>() / 2400.0).sqrt()
+        };
+        let (l_to_r, r_to_l) = (leak_rms([1000.0, 16000.0], true), leak_rms([1000.0, 16000.0], false));
+        assert!(l_to_r * 10.0 < r_to_l, "L->R {} vs R->L {}", l_to_r, r_to_l);
+        // カットオフを入れ替えると経路の大小も入れ替わる
+        let (l_to_r, r_to_l) = (leak_rms([16000.0, 1000.0], true), leak_rms([16000.0, 1000.0], false));
+        assert!(r_to_l * 10.0 < l_to_r, "L->R {} vs R->L {}", l_to_r, r_to_l);
+    }
+
+    #[test]
+    fn raw_output_is_not_clamped() {
+        let peak = |raw: bool| {
+            let mut e = engine(false);
+            e.set_raw_output(raw);
+            let input = (0..480).map(|n| [(n as f32 * 0.1).sin() * 3.0, 0.0]);
+            run(&mut e, input, &UNITY).iter().map(|f| f[0].abs()).fold(0.0, f32::max)
+        };
+        assert!(peak(false) <= 1.0, "{}", peak(false));
+        assert!(peak(true) > 2.0, "{}", peak(true));
+    }
+
+    #[test]
+    fn zero_main_delay_reads_the_sample_just_written() {
+        // リングバッファを一周以上させ、古い値が残っている状態で比べる
+        let input: Vec<[f32; 2]> = (0..1200).map(|n| [(n as f32 * 0.05).sin() * 0.3, (n as f32 * 0.07).cos() * 0.3]).collect();
+        for interpolation in [Interpolation::Linear, Interpolation::Cubic] {
+            let output = |main_delay: f64| {
+                let mut e = CtcEngine::new(&EngineConfig { main_delays: [main_delay, main_delay], ..config() });
+                e.set_interpolation(interpolation);
+                run(&mut e, input.iter().copied(), &[1.0; 4])
+            };
+            let (zero, five) = (output(0.0), output(5.0));
+            assert_ne!(zero[0], [0.0, 0.0]);
+            for n in 0..zero.len() - 5 {
+                assert_eq!(zero[n], five[n + 5], "n {}", n);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_poles_replace_the_built_in_table() {
+        let (poles_a, poles_b) = ([2.0, 10.0], [1.0, 5.0]);
+        let e = CtcEngine::new(&EngineConfig { custom_poles: Some((&poles_a, &poles_b)), ..config() });
+        // ポールpは 150p Hz の一次オールパスを双一次変換したものになる
+        let expected = |p: f64| {
+            let omega = std::f64::consts::PI * p * 150.0 / SR as f64;
+            ((omega - 1.0) / (omega + 1.0)) as f32
+        };
+        let coefficients = e.coefficients();
+        assert_eq!(coefficients.allpass_a.len(), poles_a.len());
+        assert_eq!(coefficients.allpass_b.len(), poles_b.len());
+        for (&alpha, &p) in coefficients.allpass_a.iter().zip(&poles_a).chain(coefficients.allpass_b.iter().zip(&poles_b)) {
+            assert_eq!(alpha as f32, expected(p), "pole {}", p);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "same length")]
+    fn mismatched_pole_tables_are_rejected() {
+        CtcEngine::new(&EngineConfig { custom_poles: Some((&[1.0, 2.0], &[1.0])), ..config() });
+    }
+
+    #[test]
+    fn non_finite_input_is_treated_as_silence() {
+        let input = |bad: [f32; 2]| (0..2400).map(move |n| {
+            if n == 1000 { bad } else { [(n as f32 * 0.05).sin() * 0.3, (n as f32 * 0.03).sin() * 0.3] }
+        });
+        let clean = run(&mut engine(true), input([0.0, 0.0]), &[1.0; 4]);
+        for bad in [[f32::NAN, 0.0], [0.0, f32::INFINITY], [f32::NEG_INFINITY, f32::NAN]] {
+            let out = run(&mut engine(true), input(bad), &[1.0; 4]);
+            assert!(out.iter().flatten().all(|x| x.is_finite()));
+            // 不正なサンプルだけを0に置き換えたのと同じ出力に戻り、状態は壊れない
+            assert!(out[1001..].iter().zip(&clean[1001..]).all(|(a, b)| (a[0] - b[0]).abs() < 1e-6 && (a[1] - b[1]).abs() < 1e-6));
+            assert!(out[2000..].iter().any(|f| f[0].abs() > 0.05));
+        }
+    }
+
+    #[test]
+    fn block_processing_matches_per_frame_processing() {
+        let input: Vec<[f32; 2]> = (0..2000).map(|n| [(n as f32 * 0.05).sin() * 0.4, (n as f32 * 0.11).cos() * 0.4]).collect();
+        let expected = run(&mut engine(true), input.iter().copied(), &[1.0; 4]);
+        for block_size in [1, 64, 256, 1000] {
+            let mut e = engine(true);
+            let mut output = vec![[0.0; 2]; input.len()];
+            for (x, y) in input.chunks(block_size).zip(output.chunks_mut(block_size)) {
+                e.process_block(x, y, 0.7, &[1.0; 4]);
+            }
+            assert_eq!(output, expected, "block size {}", block_size);
+        }
+    }
+
+    #[test]
+    fn low_pass_snapshot_has_the_rbj_coefficients() {
+        // 48kHz・2kHzの2次バターワース (Q = 1/√2) をRBJの式で計算した値
+        let expected = [0.014401440346511207, 0.028802880693022413, 0.014401440346511207, -1.632993161855452, 0.690598923241497];
+        let coefficients = engine(false).coefficients();
+        for stages in &coefficients.low_pass {
+            assert_eq!(stages.len(), 1);
+            for (c, e) in stages[0].iter().zip(expected) {
+                assert!((c - e).abs() < 1e-9, "{:?}", stages[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn frozen_engine_ignores_updates_until_thawed() {
+        let mut e = engine(false);
+        let before = e.coefficients();
+        e.freeze(true);
+        e.set_biquad_targets([4000.0, 4000.0], 40.0, 300.0, 3.0, 0);
+        let frozen = e.coefficients();
+        assert!(frozen.frozen);
+        assert_eq!(frozen.low_pass, before.low_pass);
+        assert_eq!(frozen.high_pass, before.high_pass);
+        assert_eq!(frozen.low_shelf, before.low_shelf);
+        // 解除したあとに送り直した設定は反映される
+        e.freeze(false);
+        e.set_biquad_targets([4000.0, 4000.0], 40.0, 300.0, 3.0, 0);
+        let thawed = e.coefficients();
+        assert!(!thawed.frozen);
+        assert_ne!(thawed.low_pass, before.low_pass);
+        assert_eq!(thawed.high_pass[0], BiquadFilter::<f64>::high_pass(SR, 40.0).coefficients());
+        assert_eq!(thawed.low_shelf[0], BiquadFilter::<f64>::low_shelf(SR, 300.0, 3.0).coefficients());
+    }
+
+    #[test]
+    fn solo_modes_split_direct_and_cancellation() {
+        let response = |solo: SoloMode| {
+            let mut e = engine(false);
+            e.set_raw_output(true);
+            e.set_solo(solo);
+            e.impulse_response(64, 0.7, &[1.0; 4]).0
+        };
+        let (all, direct, crosstalk) = (response(SoloMode::All), response(SoloMode::DirectOnly), response(SoloMode::CrosstalkOnly));
+        // 左入力の直接音は左だけ、打ち消しは右にだけ出る
+        assert!(direct[0].iter().any(|&x| x != 0.0));
+        assert!(direct[1].iter().all(|&x| x == 0.0));
+        assert!(crosstalk[0].iter().all(|&x| x == 0.0));
+        assert!(crosstalk[1].iter().any(|&x| x != 0.0));
+        for ch in 0..2 {
+            for n in 0..64 {
+                assert!((all[ch][n] - (direct[ch][n] + crosstalk[ch][n])).abs() < 1e-6, "ch {} n {}", ch, n);
+            }
+        }
+    }
+
+    #[test]
+    fn more_shadow_stages_attenuate_the_stopband_more() {
+        // 遮蔽2kHzに対して8kHzの正弦波を左に入れ、右に出る打ち消し信号のRMSを段数ごとに比べる
+        let leak_rms = |stages: usize| {
+            let mut e = CtcEngine::new(&EngineConfig { shadow_stages: [stages, stages], ..config() });
+            e.set_solo(SoloMode::CrosstalkOnly);
+            let w = 2.0 * std::f32::consts::PI * 8000.0 / SR;
+            let out = run(&mut e, (0..4800).map(|n| [(w * n as f32).sin() * 0.5, 0.0]), &[1.0; 4]);
+            (out[2400..].iter().map(|f| (f[1] as f64).powi(2)).sum::<f64>() / 2400.0).sqrt()
+        };
+        let rms: Vec<f64> = (1..=MAX_SHADOW_STAGES).map(leak_rms).collect();
+        for pair in rms.windows(2) {
+            assert!(pair[1] < pair[0] * 0.5, "{:?}", rms);
+        }
+    }
+
+    #[test]
+    fn delay_target_change_ramps_the_delay() {
+        let mut e = engine(false);
+        e.set_delay_targets([30.0, 10.0], [100.0, 0.0]);
+        let ramp_len = (DELAY_RAMP_MS * SR / 1000.0) as usize;
+        let mut main_delays = Vec::new();
+        for _ in 0..ramp_len + 10 {
+            e.process([0.1, 0.1], 0.7, &UNITY);
+            main_delays.push(e.main_delay_l);
+        }
+        // 1サンプルあたりの変化は小さく、単調に増えてDELAY_RAMP_MSで目標に届く
+        assert!(main_delays[0] > 0.0 && main_delays[0] < 1.0, "{}", main_delays[0]);
+        assert!(main_delays.windows(2).all(|w| w[1] >= w[0] && w[1] - w[0] < 1.0));
+        assert!(main_delays[ramp_len / 2] > 40.0 && main_delays[ramp_len / 2] < 60.0, "{}", main_delays[ramp_len / 2]);
+        assert_eq!(main_delays[ramp_len - 1], 100.0);
+        assert_eq!(e.ct_delay_l, 30.0);
+        assert_eq!([e.ct_delay_r, e.main_delay_r], [10.0, 0.0]);
+    }
+
+    #[test]
+    fn allpass_bypass_removes_the_phase_network() {
+        let direct_response = |bypass: bool| {
+            let mut e = engine(false);
+            e.set_allpass_bypass(bypass);
+            e.set_solo(SoloMode::DirectOnly);
+            e.impulse_response(256, 0.7, &UNITY).0[0].clone()
+        };
+        // バイパスすると直接音はインパルスのまま (0dBのローシェルフしか通らない)
+        let bypassed = direct_response(true);
+        assert!((bypassed[0] - 1.0).abs() < 1e-6, "{}", bypassed[0]);
+        assert!(bypassed[1..].iter().all(|x| x.abs() < 1e-6));
+        // オールパスを通すとエネルギーは保ったまま時間方向に広がる
+        let filtered = direct_response(false);
+        assert!(filtered[0] < 0.9, "{}", filtered[0]);
+        assert!(filtered[1..].iter().map(|x| x.abs()).fold(0.0, f32::max) > 0.05);
+        let energy = filtered.iter().map(|&x| x as f64 * x as f64).sum::<f64>();
+        assert!((energy - 1.0).abs() < 0.05, "{}", energy);
+    }
+
+    #[test]
+    fn pre_and_de_emphasis_cancel() {
+        // オールパスと遅延の補間を通らない直接音だけを見る
+        let mut e = engine(false);
+        e.set_allpass_bypass(true);
+        e.set_solo(SoloMode::DirectOnly);
+        e.set_raw_output(true);
+        e.set_emphasis(Some(6.0));
+        let w = 2.0 * std::f32::consts::PI * 12000.0 / SR;
+        let input: Vec<[f32; 2]> = (0..4800).map(|n| [(w * n as f32).sin() * 0.5, (0.3 * n as f32).sin() * 0.5]).collect();
+        let out = run(&mut e, input.iter().copied(), &UNITY);
+        for (n, (x, y)) in input.iter().zip(&out).enumerate() {
+            assert!((x[0] - y[0]).abs() < 1e-5 && (x[1] - y[1]).abs() < 1e-5, "n {}: {:?} vs {:?}", n, x, y);
+        }
+        // 途中の信号は高域が持ち上がっている (12kHzはシェルフの肩に近く、6dBには届かない)
+        let [mut pre, _] = e.pre_emphasis.take().unwrap();
+        let boosted = input.iter().map(|x| pre.process(x[0] as f64)).skip(2400).fold(0.0, |m: f64, y| m.max(y.abs()));
+        assert!(boosted > 0.5 * 1.5, "{}", boosted);
+    }
+
+    #[test]
+    fn tilt_raises_highs_by_the_octave_slope() {
+        // 出力段のチルトだけに正弦波を通し、定常状態の振幅をdBで測る
+        let level_db = |freq: f32| {
+            let mut e = engine(false);
+            e.set_tilt(3.0);
+            let [mut tilt, _] = e.tilt.take().unwrap();
+            let w = 2.0 * std::f64::consts::PI * freq as f64 / SR as f64;
+            let peak = (0..SR as usize).map(|n| tilt.process((w * n as f64).sin()) * e.tilt_gain)
+                .skip(SR as usize / 2)
+                .fold(0.0, |m: f64, y| m.max(y.abs()));
+            20.0 * peak.log10()
+        };
+        // 125 Hzから8 kHzまでは6オクターブなので、3 dB/octで約18 dB上がる
+        let rise = level_db(8000.0) - level_db(125.0);
+        assert!((rise - 18.0).abs() < 0.5, "{} dB", rise);
+        // 中心の1 kHz付近はほぼ0 dBのまま
+        assert!(level_db(1000.0).abs() < 0.5, "{} dB", level_db(1000.0));
+    }
+
+    #[test]
+    fn sample_rate_change_keeps_the_low_pass_corner() {
+        let mut e = CtcEngine::new(&EngineConfig { sample_rate: 44100.0, ..config() });
+        e.set_sample_rate(SR);
+        assert_eq!(e.coefficients().low_pass, engine(false).coefficients().low_pass);
+        // 2kHzの正弦波を遮蔽ローパスに通すと、新しいレートでも-3dBになる
+        let w = 2.0 * std::f64::consts::PI * 2000.0 / SR as f64;
+        let peak = (0..SR as usize).map(|n| e.low_pass_l.process((w * n as f64).sin()))
+            .skip(SR as usize / 2)
+            .fold(0.0, |m: f64, y| m.max(y.abs()));
+        assert!((20.0 * peak.log10() + 3.01).abs() < 0.05, "{} dB", 20.0 * peak.log10());
+        // 遅延は時間を保つようにサンプル数を換算する
+        assert!((e.ct_delay_l - 10.0 * SR as f64 / 44100.0).abs() < 1e-9, "{}", e.ct_delay_l);
+    }
+}
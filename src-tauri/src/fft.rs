@@ -0,0 +1,116 @@
+use std::f32::consts::PI;
+
+// 反復型の基数2複素FFT (その場計算)。
+// rustfftは依存に入れていないので、スペクトル表示とHRIRの畳み込みで使う2のべきの長さだけを自前で扱う。
+// rustfftに置き換えるときは forward / inverse の呼び出し側 (spectrum.rs, hrtf.rs) をそのまま差し替えられる
+pub struct Fft {
+    size: usize,
+    twiddles: Vec<(f32, f32)>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two());
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let phase = -2.0 * PI * k as f32 / size as f32;
+                (phase.cos(), phase.sin())
+            })
+            .collect();
+        Self { size, twiddles }
+    }
+
+    pub fn forward(&self, re: &mut [f32], im: &mut [f32]) {
+        let n = self.size;
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let step = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (wr, wi) = self.twiddles[k * step];
+                    let a = start + k;
+                    let b = a + len / 2;
+                    let tr = re[b] * wr - im[b] * wi;
+                    let ti = re[b] * wi + im[b] * wr;
+                    re[b] = re[a] - tr;
+                    im[b] = im[a] - ti;
+                    re[a] += tr;
+                    im[a] += ti;
+                }
+            }
+            len *= 2;
+        }
+    }
+
+    // 共役を取って順変換し、1/nで正規化する
+    pub fn inverse(&self, re: &mut [f32], im: &mut [f32]) {
+        im.iter_mut().for_each(|x| *x = -*x);
+        self.forward(re, im);
+        let scale = 1.0 / self.size as f32;
+        re.iter_mut().for_each(|x| *x *= scale);
+        im.iter_mut().for_each(|x| *x *= -scale);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_lands_in_its_bin() {
+        let n = 64;
+        let bin = 5;
+        let fft = Fft::new(n);
+        let mut re: Vec<f32> = (0..n).map(|i| (2.0 * PI * (bin * i) as f32 / n as f32).sin()).collect();
+        let mut im = vec![0.0; n];
+        fft.forward(&mut re, &mut im);
+        for k in 0..n {
+            let mag = re[k].hypot(im[k]);
+            let expected = if k == bin || k == n - bin { n as f32 / 2.0 } else { 0.0 };
+            assert!((mag - expected).abs() < 1e-3, "bin {}: {}", k, mag);
+        }
+        // sinは虚部に -n/2 として現れる
+        assert!((im[bin] + n as f32 / 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn impulse_is_flat() {
+        let n = 32;
+        let fft = Fft::new(n);
+        let mut re = vec![0.0; n];
+        let mut im = vec![0.0; n];
+        re[0] = 1.0;
+        fft.forward(&mut re, &mut im);
+        assert!(re.iter().all(|&x| (x - 1.0).abs() < 1e-6));
+        assert!(im.iter().all(|&x| x.abs() < 1e-6));
+    }
+
+    #[test]
+    fn inverse_round_trips() {
+        let n = 256;
+        let fft = Fft::new(n);
+        let orig_re: Vec<f32> = (0..n).map(|i| ((i * 7919) % 97) as f32 / 97.0 - 0.5).collect();
+        let orig_im: Vec<f32> = (0..n).map(|i| ((i * 104729) % 89) as f32 / 89.0 - 0.5).collect();
+        let (mut re, mut im) = (orig_re.clone(), orig_im.clone());
+        fft.forward(&mut re, &mut im);
+        fft.inverse(&mut re, &mut im);
+        for i in 0..n {
+            assert!((re[i] - orig_re[i]).abs() < 1e-5);
+            assert!((im[i] - orig_im[i]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_non_power_of_two() {
+        Fft::new(48);
+    }
+}
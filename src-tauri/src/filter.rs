@@ -1,29 +1,42 @@
 use std::f64::consts::PI;
 
+use num_traits::Float;
+
 pub enum PrimaryFilterType {
     AllPass,
     HighPass,
     LowPass,
+    DcBlock,
+}
+
+pub trait Processable<T: Float = f64> {
+    fn process(&mut self, input: T) -> T;
+    fn reset(&mut self);
 }
 
-pub trait Processable {
-    fn process(&mut self, input: f64) -> f64;
+// これより小さい状態値は0に落とす (非正規化数による処理負荷の増大を防ぐ)
+const DENORMAL_THRESHOLD: f64 = 1e-30;
+
+// 係数はf64で計算してから各サンプル型に変換する
+#[inline(always)]
+fn cast<F: Float>(x: f64) -> F {
+    F::from(x).unwrap()
 }
 
-pub struct PrimaryFilter {
+pub struct PrimaryFilter<F: Float = f64> {
     filter_type: PrimaryFilterType,
-    alpha: f64,
-    prev_in: f64,
-    prev_out: f64,
+    alpha: F,
+    prev_in: F,
+    prev_out: F,
 }
 
-impl PrimaryFilter {
+impl<F: Float> PrimaryFilter<F> {
     pub fn all_pass(alpha: f32) -> Self {
         Self {
             filter_type: PrimaryFilterType::AllPass,
-            alpha: alpha as f64,
-            prev_in: 0.0,
-            prev_out: 0.0,
+            alpha: cast(alpha as f64),
+            prev_in: F::zero(),
+            prev_out: F::zero(),
         }
     }
 
@@ -33,9 +46,9 @@ impl PrimaryFilter {
         let alpha = rc / (rc + dt);
         Self {
             filter_type: PrimaryFilterType::HighPass,
-            alpha,
-            prev_in: 0.0,
-            prev_out: 0.0,
+            alpha: cast(alpha),
+            prev_in: F::zero(),
+            prev_out: F::zero(),
         }
     }
 
@@ -45,16 +58,29 @@ impl PrimaryFilter {
         let alpha = dt / (rc + dt);
         Self {
             filter_type: PrimaryFilterType::LowPass,
-            alpha,
-            prev_in: 0.0,
-            prev_out: 0.0,
+            alpha: cast(alpha),
+            prev_in: F::zero(),
+            prev_out: F::zero(),
+        }
+    }
+
+    pub fn alpha(&self) -> f64 {
+        self.alpha.to_f64().unwrap()
+    }
+
+    pub fn dc_block(pole: f32) -> Self {
+        Self {
+            filter_type: PrimaryFilterType::DcBlock,
+            alpha: cast(pole as f64),
+            prev_in: F::zero(),
+            prev_out: F::zero(),
         }
     }
 }
 
-impl Processable for PrimaryFilter {
-    fn process(&mut self, input: f64) -> f64 {
-        let out64 = match self.filter_type {
+impl<F: Float> Processable<F> for PrimaryFilter<F> {
+    fn process(&mut self, input: F) -> F {
+        let mut out64 = match self.filter_type {
             PrimaryFilterType::AllPass => {
                 self.alpha * input + self.prev_in - self.alpha * self.prev_out
             }
@@ -64,35 +90,54 @@ impl Processable for PrimaryFilter {
             PrimaryFilterType::LowPass => {
                 self.prev_out + self.alpha * (input - self.prev_out)
             }
+            PrimaryFilterType::DcBlock => {
+                input - self.prev_in + self.alpha * self.prev_out
+            }
         };
+        if out64.abs() < cast(DENORMAL_THRESHOLD) {
+            out64 = F::zero();
+        }
         self.prev_in = input;
         self.prev_out = out64;
         out64
     }
+
+    fn reset(&mut self) {
+        self.prev_in = F::zero();
+        self.prev_out = F::zero();
+    }
 }
 
-pub struct BiquadFilter {
-    b0: f64,
-    b1: f64, b2: f64,
-    a1: f64, a2: f64,
-    z1: f64, z2: f64,
+pub struct BiquadFilter<F: Float = f64> {
+    b0: F,
+    b1: F, b2: F,
+    a1: F, a2: F,
+    z1: F, z2: F,
 }
 
-impl BiquadFilter {
+impl<F: Float> BiquadFilter<F> {
     fn new(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
         Self {
-            b0: b0 / a0,
-            b1: b1 / a0,
-            b2: b2 / a0,
-            a1: a1 / a0,
-            a2: a2 / a0,
-            z1: 0.0,
-            z2: 0.0
+            b0: cast(b0 / a0),
+            b1: cast(b1 / a0),
+            b2: cast(b2 / a0),
+            a1: cast(a1 / a0),
+            a2: cast(a2 / a0),
+            z1: F::zero(),
+            z2: F::zero(),
         }
     }
 
+    // a0で正規化済みの [b0, b1, b2, a1, a2]
+    pub fn coefficients(&self) -> [f64; 5] {
+        [self.b0, self.b1, self.b2, self.a1, self.a2].map(|c| c.to_f64().unwrap())
+    }
+
     pub fn low_pass(sample_rate: f32, cutoff: f32) -> Self {
-        let q = 0.70710678118;
+        Self::low_pass_q(sample_rate, cutoff, 0.70710678118)
+    }
+
+    pub fn low_pass_q(sample_rate: f32, cutoff: f32, q: f64) -> Self {
         let omega = 2.0 * PI * cutoff as f64 / sample_rate as f64;
         let cos_w = omega.cos();
         let alpha = omega.sin() / (2.0 * q);
@@ -123,13 +168,63 @@ impl BiquadFilter {
         )
     }
 
-    pub fn low_shelf(sample_rate: f32, cutoff: f32, gain_db: f32) -> Self {
-        let q = 0.707;
-        let a = 10.0f64.powf(gain_db as f64 / 40.0);
+    // RBJの定スカート利得型。中心周波数での利得はQになる
+    pub fn band_pass(sample_rate: f32, center: f32, q: f64) -> Self {
+        let omega = 2.0 * PI * center as f64 / sample_rate as f64;
+        let cos_w = omega.cos();
+        let sin_w = omega.sin();
+        let alpha = sin_w / (2.0 * q);
+
+        Self::new(
+            sin_w / 2.0,
+            0.0,
+            -sin_w / 2.0,
+            1.0 + alpha,
+            -2.0 * cos_w,
+            1.0 - alpha,
+        )
+    }
+
+    // 振幅を変えずに位相だけを回す2次のオールパス (cutoffで-180°)
+    pub fn all_pass(sample_rate: f32, cutoff: f32, q: f64) -> Self {
         let omega = 2.0 * PI * cutoff as f64 / sample_rate as f64;
         let cos_w = omega.cos();
-        let beta = (a + 1.0 / a) * (1.0 / q - 1.0) + 2.0;
-        let alpha = omega.sin() / 2.0 * beta.max(0.0).sqrt();
+        let alpha = omega.sin() / (2.0 * q);
+
+        Self::new(
+            1.0 - alpha,
+            -2.0 * cos_w,
+            1.0 + alpha,
+            1.0 + alpha,
+            -2.0 * cos_w,
+            1.0 - alpha,
+        )
+    }
+
+    // RBJのピーキングEQ。centerでgain_dbだけ持ち上げる (負なら削る)
+    pub fn peaking(sample_rate: f32, center: f32, gain_db: f32, q: f64) -> Self {
+        let a = 10.0f64.powf(gain_db as f64 / 40.0);
+        let omega = 2.0 * PI * center as f64 / sample_rate as f64;
+        let cos_w = omega.cos();
+        let alpha = omega.sin() / (2.0 * q);
+
+        Self::new(
+            1.0 + alpha * a,
+            -2.0 * cos_w,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos_w,
+            1.0 - alpha / a,
+        )
+    }
+
+    pub fn low_shelf(sample_rate: f32, cutoff: f32, gain_db: f32) -> Self {
+        Self::low_shelf_s(sample_rate, cutoff, gain_db, 1.0)
+    }
+
+    // slopeはRBJのシェルフスロープS (0 < S <= 1)。S=1でオーバーシュートのない最急の傾きになる
+    pub fn low_shelf_s(sample_rate: f32, cutoff: f32, gain_db: f32, slope: f64) -> Self {
+        let (a, cos_w, alpha) = shelf_params(sample_rate, cutoff, gain_db, slope);
 
         Self::new(
             a * ((a + 1.0) - (a - 1.0) * cos_w + 2.0 * a.sqrt() * alpha),
@@ -140,21 +235,405 @@ impl BiquadFilter {
             (a + 1.0) + (a - 1.0) * cos_w - 2.0 * a.sqrt() * alpha,
         )
     }
+
+    pub fn high_shelf(sample_rate: f32, cutoff: f32, gain_db: f32) -> Self {
+        Self::high_shelf_s(sample_rate, cutoff, gain_db, 1.0)
+    }
+
+    pub fn high_shelf_s(sample_rate: f32, cutoff: f32, gain_db: f32, slope: f64) -> Self {
+        let (a, cos_w, alpha) = shelf_params(sample_rate, cutoff, gain_db, slope);
+
+        Self::new(
+            a * ((a + 1.0) + (a - 1.0) * cos_w + 2.0 * a.sqrt() * alpha),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w),
+            a * ((a + 1.0) + (a - 1.0) * cos_w - 2.0 * a.sqrt() * alpha),
+            (a + 1.0) - (a - 1.0) * cos_w + 2.0 * a.sqrt() * alpha,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos_w),
+            (a + 1.0) - (a - 1.0) * cos_w - 2.0 * a.sqrt() * alpha,
+        )
+    }
+
+    fn first_order_low_pass(sample_rate: f32, cutoff: f32) -> Self {
+        let k = (PI * cutoff as f64 / sample_rate as f64).tan();
+        Self::new(k, k, 0.0, k + 1.0, k - 1.0, 0.0)
+    }
 }
 
-impl Processable for BiquadFilter {
-    fn process(&mut self, input: f64) -> f64 {
+// シェルフ共通の (A, cos(w), alpha)
+fn shelf_params(sample_rate: f32, cutoff: f32, gain_db: f32, slope: f64) -> (f64, f64, f64) {
+    let a = 10.0f64.powf(gain_db as f64 / 40.0);
+    let omega = 2.0 * PI * cutoff as f64 / sample_rate as f64;
+    let beta = (a + 1.0 / a) * (1.0 / slope - 1.0) + 2.0;
+    (a, omega.cos(), omega.sin() / 2.0 * beta.max(0.0).sqrt())
+}
+
+impl<F: Float> Processable<F> for BiquadFilter<F> {
+    fn process(&mut self, input: F) -> F {
         let output = self.b0 * input + self.z1;
 
         if output.is_finite() {
             self.z1 = self.b1 * input - self.a1 * output + self.z2;
             self.z2 = self.b2 * input - self.a2 * output;
-            if output.abs() < f64::EPSILON { 0.0 }
+            if output.abs() < F::epsilon() { F::zero() }
             else { output }
         } else {
-            self.z1 = 0.0;
-            self.z2 = 0.0;
-            0.0
+            self.z1 = F::zero();
+            self.z2 = F::zero();
+            F::zero()
         }
     }
-}
\ No newline at end of file
+
+    fn reset(&mut self) {
+        self.z1 = F::zero();
+        self.z2 = F::zero();
+    }
+}
+
+// 1つのカスケードに並べられる2次セクションの上限 (スピーカーEQの帯域数も収まるようにする)
+pub const MAX_CASCADE_STAGES: usize = 16;
+
+pub struct BiquadCascade {
+    // 音声スレッドで作り直してもヒープを使わないように、固定長の配列の先頭len個だけを使う
+    stages: [BiquadFilter; MAX_CASCADE_STAGES],
+    len: usize,
+}
+
+impl BiquadCascade {
+    pub fn new(stages: impl IntoIterator<Item = BiquadFilter>) -> Self {
+        let mut cascade = Self {
+            stages: std::array::from_fn(|_| BiquadFilter::new(1.0, 0.0, 0.0, 1.0, 0.0, 0.0)),
+            len: 0,
+        };
+        for stage in stages {
+            assert!(cascade.len < MAX_CASCADE_STAGES, "a cascade holds at most {} stages", MAX_CASCADE_STAGES);
+            cascade.stages[cascade.len] = stage;
+            cascade.len += 1;
+        }
+        cascade
+    }
+
+    // N次バターワースの極を2次セクション (奇数次は1次セクションを追加) に分ける
+    pub fn butterworth_low_pass(sample_rate: f32, cutoff: f32, order: usize) -> Self {
+        let n = order as f64;
+        let biquads = (0..order / 2).map(|k| {
+            let theta = if order.is_multiple_of(2) {
+                PI * (2 * k + 1) as f64 / (2.0 * n)
+            } else {
+                PI * (k + 1) as f64 / n
+            };
+            BiquadFilter::low_pass_q(sample_rate, cutoff, 1.0 / (2.0 * theta.cos()))
+        });
+        let first_order = (!order.is_multiple_of(2)).then(|| BiquadFilter::first_order_low_pass(sample_rate, cutoff));
+        Self::new(biquads.chain(first_order))
+    }
+
+    pub fn coefficients(&self) -> Vec<[f64; 5]> {
+        self.stages[..self.len].iter().map(|f| f.coefficients()).collect()
+    }
+}
+
+impl Processable for BiquadCascade {
+    fn process(&mut self, input: f64) -> f64 {
+        self.stages[..self.len].iter_mut().fold(input, |acc, f| f.process(acc))
+    }
+
+    fn reset(&mut self) {
+        self.stages[..self.len].iter_mut().for_each(|f| f.reset());
+    }
+}
+
+pub struct LinkwitzRiley {
+    low: [BiquadFilter; 2],
+    high: [BiquadFilter; 2],
+}
+
+impl LinkwitzRiley {
+    // 4次 (2次バターワースを2段) のクロスオーバー。low + high は全域通過になる
+    pub fn new(sample_rate: f32, crossover_hz: f32) -> Self {
+        Self {
+            low: [
+                BiquadFilter::low_pass(sample_rate, crossover_hz),
+                BiquadFilter::low_pass(sample_rate, crossover_hz),
+            ],
+            high: [
+                BiquadFilter::high_pass(sample_rate, crossover_hz),
+                BiquadFilter::high_pass(sample_rate, crossover_hz),
+            ],
+        }
+    }
+
+    pub fn split(&mut self, input: f64) -> (f64, f64) {
+        let low = self.low.iter_mut().fold(input, |acc, f| f.process(acc));
+        let high = self.high.iter_mut().fold(input, |acc, f| f.process(acc));
+        (low, high)
+    }
+}
+
+pub struct Crossfaded<P: Processable> {
+    current: P,
+    next: Option<P>,
+    fade_pos: usize,
+    fade_len: usize,
+}
+
+impl<P: Processable> Crossfaded<P> {
+    pub fn new(filter: P) -> Self {
+        Self {
+            current: filter,
+            next: None,
+            fade_pos: 0,
+            fade_len: 0,
+        }
+    }
+
+    // 切り替え中なら切り替え先のフィルタ
+    pub fn target(&self) -> &P {
+        self.next.as_ref().unwrap_or(&self.current)
+    }
+
+    // 新旧のフィルタを並行して動かし、出力を徐々に切り替える
+    pub fn fade_to(&mut self, filter: P, fade_len: usize) {
+        if fade_len == 0 {
+            self.current = filter;
+            self.next = None;
+            return;
+        }
+        if let Some(next) = self.next.take() {
+            self.current = next;
+        }
+        self.next = Some(filter);
+        self.fade_pos = 0;
+        self.fade_len = fade_len;
+    }
+}
+
+impl<P: Processable> Processable for Crossfaded<P> {
+    fn process(&mut self, input: f64) -> f64 {
+        let out_current = self.current.process(input);
+        let Some(next) = self.next.as_mut() else {
+            return out_current;
+        };
+        let out_next = next.process(input);
+        self.fade_pos += 1;
+        let t = self.fade_pos as f64 / self.fade_len as f64;
+        if self.fade_pos >= self.fade_len {
+            self.current = self.next.take().unwrap();
+        }
+        out_current + (out_next - out_current) * t
+    }
+
+    fn reset(&mut self) {
+        if let Some(next) = self.next.take() {
+            self.current = next;
+        }
+        self.current.reset();
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 48000.0;
+
+    fn sine(freq: f64, n: usize) -> f64 {
+        (2.0 * PI * freq * n as f64 / SR as f64).sin()
+    }
+
+    // 正弦波を1秒通し、過渡応答が収まった後半のRMS比をdBで返す
+    fn gain_db(mut process: impl FnMut(f64) -> f64, freq: f64) -> f64 {
+        let len = SR as usize;
+        let (mut in_sq, mut out_sq) = (0.0, 0.0);
+        for n in 0..len {
+            let x = sine(freq, n);
+            let y = process(x);
+            if n >= len / 2 {
+                in_sq += x * x;
+                out_sq += y * y;
+            }
+        }
+        10.0 * (out_sq / in_sq).log10()
+    }
+
+    // 100Hzの正弦波のピークでカットオフを切り替え、その後の1サンプルあたりの最大変化量を返す
+    fn max_step_after_switch(fade_len: usize) -> f64 {
+        let switch_at = 2400 + 120;
+        let mut filter = Crossfaded::new(BiquadFilter::low_pass(SR, 1000.0));
+        let mut prev = 0.0;
+        let mut max_step: f64 = 0.0;
+        for n in 0..switch_at + 1000 {
+            if n == switch_at {
+                filter.fade_to(BiquadFilter::low_pass(SR, 2000.0), fade_len);
+            }
+            let y = filter.process(sine(100.0, n));
+            if n >= switch_at {
+                max_step = max_step.max((y - prev).abs());
+            }
+            prev = y;
+        }
+        max_step
+    }
+
+    #[test]
+    fn crossfade_keeps_cutoff_change_bounded() {
+        let hard = max_step_after_switch(0);
+        let faded = max_step_after_switch(256);
+        assert!(hard > 0.5, "hard swap step {}", hard);
+        assert!(faded < 0.05, "crossfaded step {}", faded);
+    }
+
+    #[test]
+    fn butterworth_cascade_is_3db_down_at_cutoff() {
+        for order in [2, 3, 4, 6] {
+            let mut lp = BiquadCascade::butterworth_low_pass(SR, 1000.0, order);
+            let db = gain_db(|x| lp.process(x), 1000.0);
+            assert!((db + 3.01).abs() < 0.1, "order {}: {} dB", order, db);
+        }
+    }
+
+    #[test]
+    fn cascade_runs_only_the_stages_it_was_given() {
+        // 上限いっぱいまで並べられ、使わない枠は処理に入らない
+        let peaks = (0..MAX_CASCADE_STAGES).map(|_| BiquadFilter::peaking(SR, 1000.0, 1.0, 1.0));
+        let mut full = BiquadCascade::new(peaks);
+        assert_eq!(full.coefficients().len(), MAX_CASCADE_STAGES);
+        let db = gain_db(|x| full.process(x), 1000.0);
+        assert!((db - MAX_CASCADE_STAGES as f64).abs() < 0.1, "{} dB", db);
+        let mut empty = BiquadCascade::new([]);
+        assert!(empty.coefficients().is_empty());
+        assert_eq!(empty.process(0.5), 0.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "at most")]
+    fn cascade_rejects_too_many_stages() {
+        BiquadCascade::new((0..=MAX_CASCADE_STAGES).map(|_| BiquadFilter::low_pass(SR, 1000.0)));
+    }
+
+    #[test]
+    fn higher_order_cascade_rolls_off_faster() {
+        let attenuation_at_4k = |order| {
+            let mut lp = BiquadCascade::butterworth_low_pass(SR, 1000.0, order);
+            gain_db(|x| lp.process(x), 4000.0)
+        };
+        let (second, fourth) = (attenuation_at_4k(2), attenuation_at_4k(4));
+        // 2オクターブ上で2次は約-24 dB、4次は約-48 dB
+        assert!(second < -23.0 && second > -27.0, "{}", second);
+        assert!(fourth < second - 20.0, "{} vs {}", fourth, second);
+    }
+
+    #[test]
+    fn linkwitz_riley_bands_sum_flat() {
+        for freq in [50.0, 200.0, 500.0, 1000.0, 2000.0, 8000.0] {
+            let mut xo = LinkwitzRiley::new(SR, 500.0);
+            let db = gain_db(|x| {
+                let (low, high) = xo.split(x);
+                low + high
+            }, freq);
+            assert!(db.abs() < 0.05, "{} Hz: {} dB", freq, db);
+        }
+        // クロスオーバー周波数では各帯域とも-6 dB
+        let mut xo = LinkwitzRiley::new(SR, 500.0);
+        let low_db = gain_db(|x| xo.split(x).0, 500.0);
+        assert!((low_db + 6.02).abs() < 0.1, "{}", low_db);
+    }
+
+    #[test]
+    fn f32_and_f64_low_pass_agree() {
+        let mut lp64 = BiquadFilter::<f64>::low_pass(SR, 1000.0);
+        let mut lp32 = BiquadFilter::<f32>::low_pass(SR, 1000.0);
+        for n in 0..2000 {
+            let x = if n == 0 { 1.0 } else { 0.0 };
+            let (y64, y32) = (lp64.process(x), lp32.process(x as f32));
+            assert!((y64 - y32 as f64).abs() < 1e-6, "sample {}: {} vs {}", n, y64, y32);
+        }
+    }
+
+    #[test]
+    fn primary_filter_tail_flushes_to_zero() {
+        let mut ap = PrimaryFilter::<f64>::all_pass(0.9);
+        ap.process(1.0);
+        let mut steps = 0;
+        while ap.prev_out != 0.0 {
+            ap.process(0.0);
+            steps += 1;
+            assert!(steps < 100_000, "tail never reached zero");
+            assert!(ap.prev_out == 0.0 || ap.prev_out.abs() >= DENORMAL_THRESHOLD, "{}", ap.prev_out);
+        }
+        assert_eq!(ap.process(0.0), 0.0);
+    }
+
+    // 係数から求めたDC (z=1) とナイキスト (z=-1) での利得 (dB)
+    fn dc_and_nyquist_db(filter: &BiquadFilter) -> (f64, f64) {
+        let [b0, b1, b2, a1, a2] = filter.coefficients();
+        let dc = (b0 + b1 + b2) / (1.0 + a1 + a2);
+        let nyquist = (b0 - b1 + b2) / (1.0 - a1 + a2);
+        (20.0 * dc.abs().log10(), 20.0 * nyquist.abs().log10())
+    }
+
+    #[test]
+    fn shelves_reach_gain_at_one_end_and_unity_at_the_other() {
+        for gain_db in [-12.0, -3.0, 6.0, 12.0] {
+            for slope in [0.5, 1.0] {
+                let (dc, nyquist) = dc_and_nyquist_db(&BiquadFilter::low_shelf_s(SR, 200.0, gain_db, slope));
+                assert!((dc - gain_db as f64).abs() < 1e-6, "low shelf {} dB S={}: dc {}", gain_db, slope, dc);
+                assert!(nyquist.abs() < 1e-6, "low shelf {} dB S={}: nyquist {}", gain_db, slope, nyquist);
+                let (dc, nyquist) = dc_and_nyquist_db(&BiquadFilter::high_shelf_s(SR, 4000.0, gain_db, slope));
+                assert!(dc.abs() < 1e-6, "high shelf {} dB S={}: dc {}", gain_db, slope, dc);
+                assert!((nyquist - gain_db as f64).abs() < 1e-6, "high shelf {} dB S={}: nyquist {}", gain_db, slope, nyquist);
+            }
+        }
+    }
+
+    // 係数から求めた周波数応答 (振幅dB, 位相rad)
+    fn response(filter: &BiquadFilter, freq: f64) -> (f64, f64) {
+        let [b0, b1, b2, a1, a2] = filter.coefficients();
+        let w = 2.0 * PI * freq / SR as f64;
+        let (c1, s1, c2, s2) = (w.cos(), -w.sin(), (2.0 * w).cos(), -(2.0 * w).sin());
+        let (nr, ni) = (b0 + b1 * c1 + b2 * c2, b1 * s1 + b2 * s2);
+        let (dr, di) = (1.0 + a1 * c1 + a2 * c2, a1 * s1 + a2 * s2);
+        let mag = ((nr * nr + ni * ni) / (dr * dr + di * di)).sqrt();
+        (20.0 * mag.log10(), ni.atan2(nr) - di.atan2(dr))
+    }
+
+    #[test]
+    fn band_pass_peaks_at_center_with_symmetric_edges() {
+        let (center, q) = (1000.0, 2.0);
+        let bp = BiquadFilter::band_pass(SR, center as f32, q);
+        let (peak, _) = response(&bp, center);
+        assert!((peak - 20.0 * q.log10()).abs() < 1e-6, "peak {}", peak);
+        // -3dBの点は中心から幾何対称に f0 * (sqrt(1 + 1/4Q^2) ± 1/2Q)
+        let half = (1.0 + 1.0 / (4.0 * q * q)).sqrt();
+        let (lo, _) = response(&bp, center * (half - 0.5 / q));
+        let (hi, _) = response(&bp, center * (half + 0.5 / q));
+        assert!((lo - peak + 3.01).abs() < 0.1, "lower edge {} dB", lo - peak);
+        assert!((hi - peak + 3.01).abs() < 0.1, "upper edge {} dB", hi - peak);
+        assert!((lo - hi).abs() < 0.05, "edges {} / {}", lo, hi);
+    }
+
+    #[test]
+    fn all_pass_keeps_magnitude_and_inverts_at_cutoff() {
+        let ap = BiquadFilter::all_pass(SR, 1000.0, 0.707);
+        for freq in [20.0, 200.0, 1000.0, 5000.0, 20000.0] {
+            let (db, _) = response(&ap, freq);
+            assert!(db.abs() < 1e-9, "{} Hz: {} dB", freq, db);
+        }
+        let (_, phase) = response(&ap, 1000.0);
+        assert!((phase.rem_euclid(2.0 * PI) - PI).abs() < 1e-6, "phase {}", phase.to_degrees());
+    }
+
+    #[test]
+    fn steeper_shelf_slope_narrows_the_transition() {
+        // カットオフを挟んだ1オクターブでの利得の変化量
+        let swing = |filter: BiquadFilter| {
+            let (below, _) = response(&filter, 1000.0 / 2f64.sqrt());
+            let (above, _) = response(&filter, 1000.0 * 2f64.sqrt());
+            (above - below).abs()
+        };
+        let slopes = [0.3, 0.6, 1.0];
+        let low = slopes.map(|s| swing(BiquadFilter::low_shelf_s(SR, 1000.0, 12.0, s)));
+        let high = slopes.map(|s| swing(BiquadFilter::high_shelf_s(SR, 1000.0, 12.0, s)));
+        for swings in [low, high] {
+            assert!(swings[0] < swings[1] && swings[1] < swings[2], "{:?}", swings);
+        }
+    }
+}
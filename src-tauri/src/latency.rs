@@ -0,0 +1,153 @@
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::time::{Duration, Instant};
+
+use cpal::{FromSample, Sample, traits::{DeviceTrait, StreamTrait}};
+use ringbuf::traits::{Consumer, Producer, Split};
+
+use crate::AudioError;
+
+const CLICK_AMPLITUDE: f32 = 0.5;
+const CLICK_LEN: usize = 4;
+// クリックを鳴らす前の待ち時間。この間の入力をノイズレベルの推定に使う
+const PRE_ROLL_MS: usize = 200;
+const MIN_THRESHOLD: f32 = 0.02;
+const NOISE_FACTOR: f32 = 4.0;
+
+// リングバッファにコールバック2回分の余裕を持たせる最小のレイテンシ (ms)。
+// 1回のコールバックでbuffer_size×channelsサンプルが書き込まれ、リングバッファはlatency×channelsサンプル分を持つ
+pub fn recommended_latency_ms(sample_rate: u32, buffer_size: u32, channels: u16) -> usize {
+    let channels = channels.max(1) as usize;
+    let samples = 2 * buffer_size as usize * channels;
+    (samples * 1000).div_ceil(sample_rate.max(1) as usize * channels)
+}
+
+// 録音の先頭 `start` サンプルからノイズレベルを見積もり、それを超えた最初の位置を `start` からの距離で返す
+pub fn detect_click(recording: &[f32], start: usize) -> Option<usize> {
+    let start = start.min(recording.len());
+    let noise = recording[..start].iter().fold(0.0f32, |m, x| m.max(x.abs()));
+    let threshold = (noise * NOISE_FACTOR).max(MIN_THRESHOLD);
+    recording[start..].iter().position(|x| x.abs() > threshold)
+}
+
+// 出力にクリックを1回鳴らし、入力 (ループバック) に戻ってくるまでのフレーム数を測る。
+// クリックが戻ってこなければOk(None)、ストリームを開けなければErrを返す
+pub fn measure_round_trip<T>(
+    input: &cpal::Device,
+    output: &cpal::Device,
+    config: &cpal::StreamConfig,
+    timeout: Duration,
+) -> Result<Option<usize>, AudioError>
+where
+    T: cpal::SizedSample + FromSample<f32> + Send + 'static,
+    f32: cpal::FromSample<T>,
+{
+    let sample_rate = config.sample_rate as usize;
+    let channels = config.channels as usize;
+    let pre_roll = PRE_ROLL_MS * sample_rate / 1000;
+    let capacity = pre_roll + timeout.as_millis() as usize * sample_rate / 1000;
+
+    let rb = ringbuf::HeapRb::<f32>::new(capacity);
+    let (mut prod, mut cons) = rb.split();
+
+    // 入力側で受け取ったフレーム数。クリックを書き込んだ時点の値を基準にする
+    let input_frames = Arc::new(AtomicUsize::new(0));
+    let click_at = Arc::new(AtomicUsize::new(usize::MAX));
+    let overflow = Arc::new(AtomicBool::new(false));
+
+    let input_frames_in = Arc::clone(&input_frames);
+    let overflow_in = Arc::clone(&overflow);
+    let input_fn = move |data: &[T], _: &cpal::InputCallbackInfo| {
+        for frame in data.chunks_exact(channels) {
+            if prod.try_push(frame[0].to_sample::<f32>()).is_err() {
+                overflow_in.store(true, Ordering::Relaxed);
+                return;
+            }
+        }
+        input_frames_in.fetch_add(data.len() / channels, Ordering::Relaxed);
+    };
+
+    let input_frames_out = Arc::clone(&input_frames);
+    let click_at_out = Arc::clone(&click_at);
+    let mut output_frames = 0usize;
+    let mut click_remaining = CLICK_LEN;
+    let output_fn = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        for frame in data.chunks_exact_mut(2) {
+            let value = if output_frames >= pre_roll && click_remaining > 0 {
+                if click_remaining == CLICK_LEN {
+                    click_at_out.store(input_frames_out.load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+                click_remaining -= 1;
+                CLICK_AMPLITUDE.to_sample::<T>()
+            } else {
+                T::EQUILIBRIUM
+            };
+            frame.fill(value);
+            output_frames += 1;
+        }
+    };
+
+    let err_fn = |e: cpal::StreamError| log::error!("Stream error occured: {:?}", e);
+    let output_config = cpal::StreamConfig { channels: 2, ..config.clone() };
+    let input_stream = input.build_input_stream(config, input_fn, err_fn, None)
+        .map_err(|e| AudioError::StreamFailed(format!("failed to build input stream: {}", e)))?;
+    let output_stream = output.build_output_stream(&output_config, output_fn, err_fn, None)
+        .map_err(|e| AudioError::StreamFailed(format!("failed to build output stream: {}", e)))?;
+    input_stream.play().map_err(|e| AudioError::StreamFailed(format!("failed to play input stream: {}", e)))?;
+    output_stream.play().map_err(|e| AudioError::StreamFailed(format!("failed to play output stream: {}", e)))?;
+
+    let deadline = Instant::now() + timeout + Duration::from_millis(PRE_ROLL_MS as u64);
+    while !overflow.load(Ordering::Relaxed) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(input_stream);
+    drop(output_stream);
+
+    let click_at = click_at.load(Ordering::Relaxed);
+    if click_at == usize::MAX {
+        return Ok(None);
+    }
+    let recording: Vec<f32> = cons.pop_iter().collect();
+    Ok(detect_click(&recording, click_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recommended_latency_covers_two_buffers() {
+        // 2 × 512 / 48000 = 21.3 ms を切り上げる
+        assert_eq!(recommended_latency_ms(48000, 512, 2), 22);
+        assert_eq!(recommended_latency_ms(48000, 256, 2), 11);
+        assert_eq!(recommended_latency_ms(48000, 1024, 2), 43);
+        assert_eq!(recommended_latency_ms(44100, 441, 2), 20);
+        // 容量も書き込み量もチャンネル数倍なので、チャンネル数では変わらない
+        assert_eq!(recommended_latency_ms(48000, 512, 1), 22);
+        assert_eq!(recommended_latency_ms(48000, 512, 8), 22);
+    }
+
+    // 小さなノイズの上に、startから `delay` サンプル後にだけクリックが戻ってくる録音
+    fn recording(start: usize, delay: Option<usize>) -> Vec<f32> {
+        let mut rec: Vec<f32> = (0..start + 2000).map(|n| 0.005 * (n as f32 * 0.1).sin()).collect();
+        if let Some(delay) = delay {
+            for (i, x) in rec[start + delay..start + delay + CLICK_LEN].iter_mut().enumerate() {
+                *x += CLICK_AMPLITUDE * 0.5f32.powi(i as i32);
+            }
+        }
+        rec
+    }
+
+    #[test]
+    fn click_is_found_at_its_delay() {
+        assert_eq!(detect_click(&recording(1000, Some(237)), 1000), Some(237));
+        assert_eq!(detect_click(&recording(1000, Some(0)), 1000), Some(0));
+    }
+
+    #[test]
+    fn missing_click_is_not_detected() {
+        assert_eq!(detect_click(&recording(1000, None), 1000), None);
+        // ノイズが大きければしきい値もそれに合わせて上がる
+        let loud: Vec<f32> = recording(1000, None).iter().map(|x| x * 10.0).collect();
+        assert_eq!(detect_click(&loud, 1000), None);
+    }
+}
@@ -0,0 +1,64 @@
+// チャンネルごとのピークとRMS。取り出すまで値を溜め続ける
+#[derive(Default)]
+pub struct LevelMeter {
+    peak: [f32; 2],
+    sum_sq: [f64; 2],
+    frames: usize,
+}
+
+impl LevelMeter {
+    pub fn update(&mut self, frame: [f32; 2]) {
+        for (ch, x) in frame.into_iter().enumerate() {
+            self.peak[ch] = self.peak[ch].max(x.abs());
+            self.sum_sq[ch] += (x as f64) * (x as f64);
+        }
+        self.frames += 1;
+    }
+
+    pub fn merge(&mut self, other: &LevelMeter) {
+        for ch in 0..2 {
+            self.peak[ch] = self.peak[ch].max(other.peak[ch]);
+            self.sum_sq[ch] += other.sum_sq[ch];
+        }
+        self.frames += other.frames;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    // 溜めた (ピーク, RMS) を返してリセットする
+    pub fn take(&mut self) -> ([f32; 2], [f32; 2]) {
+        let frames = self.frames.max(1) as f64;
+        let rms = self.sum_sq.map(|s| (s / frames).sqrt() as f32);
+        let peak = self.peak;
+        *self = Self::default();
+        (peak, rms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence_and_full_scale_levels() {
+        let mut meter = LevelMeter::default();
+        assert!(meter.is_empty());
+        for _ in 0..480 {
+            meter.update([0.0, 0.0]);
+        }
+        assert_eq!(meter.take(), ([0.0, 0.0], [0.0, 0.0]));
+        // 左はフルスケールの正弦波、右はフルスケールの矩形波
+        for n in 0..4800 {
+            let phase = 2.0 * std::f32::consts::PI * n as f32 / 48.0;
+            meter.update([phase.sin(), if n % 48 < 24 { 1.0 } else { -1.0 }]);
+        }
+        let (peak, rms) = meter.take();
+        assert!((peak[0] - 1.0).abs() < 1e-6 && peak[1] == 1.0, "{:?}", peak);
+        assert!((rms[0] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-4, "{:?}", rms);
+        assert!((rms[1] - 1.0).abs() < 1e-6, "{:?}", rms);
+        // 取り出したら空に戻る
+        assert!(meter.is_empty());
+    }
+}
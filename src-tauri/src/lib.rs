@@ -1,19 +1,142 @@
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32, Ordering}};
 
 use cpal::{FromSample, Sample, traits::{DeviceTrait, HostTrait, StreamTrait}};
-use dasp::{Signal, signal};
 use ringbuf::traits::{Consumer, Observer, Producer, Split};
-use tauri::Emitter;
+use tauri::{Emitter, Manager};
 
 mod filter;
+use filter::{BiquadFilter, LinkwitzRiley, Processable};
 mod ctc_engine;
-use ctc_engine::CtcEngine;
+mod smoother;
+mod auto_gain;
+mod latency;
+mod spectrum;
+mod test_tone;
+mod resampler;
+mod fft;
+mod hrtf;
+mod correlation;
+mod dither;
+mod settings;
+mod logger;
+mod adaptive_latency;
+mod ttl_cache;
+mod primer;
+mod room;
+mod level_meter;
+mod null_probe;
+mod wav;
+pub mod transaural_core;
+use smoother::LinearSmoother;
+use auto_gain::AutoGain;
+use spectrum::{SpectrumAnalyzer, FFT_SIZES};
+use test_tone::TestTone;
+use resampler::Resampler;
+use correlation::CorrelationMeter;
+use dither::Dither;
+use adaptive_latency::LatencyAdapter;
+use ttl_cache::TtlCache;
+use primer::Primer;
+use level_meter::LevelMeter;
+use null_probe::NullProbe;
+use room::{EarlyReflections, RoomParams, MAX_ROOM_DIMENSION};
+use ctc_engine::{allpass_poles, EngineCoefficients, MAX_SHADOW_STAGES, Interpolation, OutputLimiter, RenderMode, SoloMode};
+use hrtf::HrtfEngine;
+use transaural_core::{build_engine, calc_paths, AllPassParams, BenchResult, EngineParams, SpeakerEq, HrirParams, ImpulseResponse, PositionCoords};
+use transaural_core::{default_allpass_order, default_cancel_order, default_amp_factor_max, default_ceiling, default_distance_exponent, default_lowpass_cutoff_max, default_shadow_stages};
+use settings::PresetParams;
 
 struct AppState {
     abort_signal: Arc<AtomicBool>,
+    // ルーティング用スレッドが動いている間だけtrue
+    running: Arc<AtomicBool>,
+    // 終了時に待ち合わせるためのルーティング用スレッド
+    routing_thread: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
+    pause_signal: Arc<AtomicBool>,
+    gain_signal: Arc<AtomicU32>,
+    bypass_signal: Arc<AtomicBool>,
+    freeze_signal: Arc<AtomicBool>,
+    eq_update: Arc<Mutex<Option<SpeakerEq>>>,
+    filter_update: Arc<Mutex<Option<FilterParams>>>,
+    output_switch: Arc<Mutex<Option<cpal::DeviceId>>>,
+    // UIからの列挙が終わっていなければ立っている (応答しないドライバでスレッドが積み上がらないように)
+    enumerating: Arc<AtomicBool>,
+    // 直近にフロントエンドから受け取った設定。終了時にもう一度書き出す
+    last_settings: Arc<Mutex<Option<PresetParams>>>,
+    device_cache: Arc<Mutex<DeviceCache>>,
 }
 
-#[derive(serde::Serialize)]
+impl AppState {
+    fn new() -> Self {
+        Self {
+            abort_signal: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            routing_thread: Arc::new(Mutex::new(None)),
+            pause_signal: Arc::new(AtomicBool::new(false)),
+            gain_signal: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            bypass_signal: Arc::new(AtomicBool::new(false)),
+            freeze_signal: Arc::new(AtomicBool::new(false)),
+            eq_update: Arc::new(Mutex::new(None)),
+            filter_update: Arc::new(Mutex::new(None)),
+            output_switch: Arc::new(Mutex::new(None)),
+            enumerating: Arc::new(AtomicBool::new(false)),
+            last_settings: Arc::new(Mutex::new(None)),
+            device_cache: Arc::new(Mutex::new(TtlCache::new(std::time::Duration::from_millis(DEVICE_CACHE_TTL_MS)))),
+        }
+    }
+
+    // 一時停止中は出力を無音にするが、ストリームは開いたまま入力も消費し続ける
+    fn set_paused(&self, paused: bool) {
+        self.pause_signal.store(paused, Ordering::Relaxed);
+    }
+}
+
+// (ホストID, direction) ごとのデバイス一覧
+type DeviceCache = TtlCache<(Option<String>, Option<String>), Vec<AudioDeviceDescription>>;
+
+const GAIN_RAMP_MS: f32 = 10.0;
+const FADE_IN_MS: f32 = 15.0;
+const DEFAULT_BLOCK_SIZE: usize = 64;
+const MAX_BLOCK_SIZE: usize = 4096;
+const FILTER_FADE_MS: f32 = 20.0;
+const DEVICE_FADE_MS: f32 = 20.0;
+const BYPASS_FADE_MS: f32 = 30.0;
+const MAX_EQ_BANDS: usize = 16;
+const MIN_SAMPLE_RATE: f32 = 8000.0;
+const MAX_SAMPLE_RATE: f32 = 384000.0;
+// バッファサイズが不明なときに、プライミング中のリングバッファに足す余裕
+const PRIME_HEADROOM_FRAMES: usize = 4096;
+const MAX_EMPHASIS_DB: f32 = 12.0;
+const MAX_TILT_DB_PER_OCTAVE: f32 = 6.0;
+const MONO_CANCEL_WARN_DB: f32 = -6.0;
+const AUTO_GAIN_THRESHOLD: f64 = 0.891; // -1 dBFS
+const LATENCY_TIMEOUT_MS: u64 = 1000;
+const ENUMERATION_TIMEOUT_MS: u64 = 3000;
+const DEVICE_POLL_MS: u64 = 2000;
+const DEVICE_CACHE_TTL_MS: u64 = 1500;
+const CLIP_WINDOW_MS: u64 = 1000;
+const ADAPTIVE_LATENCY_STEP_MS: usize = 20;
+const MAX_ADAPTIVE_LATENCY_MS: usize = 500;
+// 起動からこの時間だけアンダーランを監視してレイテンシを調整する
+const ADAPTIVE_LATENCY_WINDOW_MS: u64 = 10_000;
+const SPECTRUM_INTERVAL_MS: u64 = 66; // 約15 Hz
+// start_thruでサンプル型を振り分けているフォーマット
+const SUPPORTED_SAMPLE_FORMATS: [cpal::SampleFormat; 12] = [
+    cpal::SampleFormat::F32,
+    cpal::SampleFormat::F64,
+    cpal::SampleFormat::I8,
+    cpal::SampleFormat::I16,
+    cpal::SampleFormat::I24,
+    cpal::SampleFormat::I32,
+    cpal::SampleFormat::I64,
+    cpal::SampleFormat::U8,
+    cpal::SampleFormat::U16,
+    cpal::SampleFormat::U24,
+    cpal::SampleFormat::U32,
+    cpal::SampleFormat::U64,
+];
+
+#[derive(serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct AudioDeviceDescription {
     id: String,
@@ -23,77 +146,455 @@ struct AudioDeviceDescription {
     is_default: bool,
 }
 
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AudioDeviceList {
+    devices: Vec<AudioDeviceDescription>,
+    truncated: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DevicesChangedPayload {
+    devices: Vec<AudioDeviceDescription>,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+enum StreamMode {
+    #[default]
+    Shared,
+    Exclusive,
+}
+
+// MidSideは測定用。2チャンネルにM=(L+R)/√2、S=(L-R)/√2を出す
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+enum OutputFormat {
+    #[default]
+    Stereo,
+    MidSide,
+}
+
+// PreProcessは入力に掛けて打ち消し処理に通す。PostProcessは処理後の出力に掛ける
+#[derive(serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum GainStage {
+    #[default]
+    PreProcess,
+    PostProcess,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct StreamModePayload {
+    requested: StreamMode,
+    actual: StreamMode,
+    reason: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+enum IssueSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigIssue {
+    severity: IssueSeverity,
+    message: String,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum AudioError {
+    InvalidParameter(String),
+    HostUnavailable(String),
+    LatencyNotDetected(String),
+    StreamFailed(String),
+    UnsupportedConfig(String),
+    Io(String),
+    DeviceNotFound(String),
+    UnsupportedFormat(String),
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Capabilities {
+    version: String,
+    hosts: Vec<String>,
+    hrtf: bool,
+    resampling: bool,
+    recording: bool,
+    sample_formats: Vec<String>,
+}
+
 #[derive(serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct Payload {
     is_finished: bool,
 }
 
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DeviceLostPayload {
+    direction: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct FeedbackRiskPayload {
+    reason: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AutoGainPayload {
+    reduction_db: f32,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CorrelationPayload {
+    correlation: f32,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MonoCompatPayload {
+    mono_compat_db: f32,
+    // MONO_CANCEL_WARN_DBを下回り、モノラルで聴くと大きく打ち消し合う
+    severe: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ClipCountPayload {
+    left: u32,
+    right: u32,
+    window_ms: u64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct UnderrunPayload {
+    frames: u32,
+    window_ms: u64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct LevelsPayload {
+    peak: [f32; 2],
+    rms: [f32; 2],
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct LatencyPayload {
+    latency_ms: usize,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SpectrumPayload {
+    bins: Vec<f32>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LatencyMeasurement {
+    samples: usize,
+    ms: f32,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct StreamConfigPayload {
+    sample_rate: u32,
+    channels: u16,
+    buffer_size: Option<u32>,
+    sample_format: String,
+}
+
+// 出力デバイスの切り替えでレートが変わったときに、監視ループから処理側へ渡す
+struct RateSwitch {
+    sample_rate: u32,
+    // 新しいレートで読み直したHRTF (本体, ヌルテストの参照)。HRIRを使っていなければNone
+    hrtf: [Option<HrtfEngine>; 2],
+}
+
 #[derive(serde::Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
-struct PositionCoords {
-    left_speaker: [f32; 2],
-    right_speaker: [f32; 2],
-    left_ear: [f32; 2],
-    right_ear: [f32; 2],
+struct FilterParams {
+    lowpass_cutoff_min: f32,
+    highpass_cutoff: f32,
+    lowshelf_cutoff: f32,
+    lowshelf_gain: f32,
+    // 指定すると遅延と振幅係数も新しい位置に合わせて動かす
+    #[serde(default)]
+    position: Option<PositionCoords>,
 }
 
 #[derive(Clone)]
 struct ThruOpt<'a> {
+    window: &'a tauri::Window,
     input: &'a cpal::Device,
     output: &'a cpal::Device,
     config: &'a cpal::StreamConfig,
+    output_sample_rate: u32,
     latency: usize,
-    position: PositionCoords,
     master_gain: f32,
-    attenuation: f32,
-    lowpass_cutoff_min: f32,
-    highpass_cutoff: f32,
-    lowshelf_cutoff: f32,
-    lowshelf_gain: f32,
     wet_dry: f32,
-    temperature: f32,
+    width: f32,
+    fade_in_ms: f32,
+    dither: bool,
+    block_size: usize,
+    auto_gain: bool,
+    crossover_hz: Option<f32>,
+    dry_lowpass_cutoff: Option<f32>,
+    adaptive_latency: bool,
+    prime_fill: Option<f32>,
+    output_format: OutputFormat,
+    gain_stage: GainStage,
+    room_reflections: Option<RoomParams>,
+    freeze_signal: Arc<AtomicBool>,
+    eq_update: Arc<Mutex<Option<SpeakerEq>>>,
+    swap_output_channels: bool,
+    null_test: bool,
+    mono_compat: bool,
+    spectrum_size: Option<usize>,
+    output_channels: [usize; 2],
+    output_channel_count: u16,
+    engine: EngineParams,
+}
+
+fn get_host(host_id: Option<&str>) -> Result<cpal::Host, AudioError> {
+    let Some(host_id) = host_id else {
+        return Ok(cpal::default_host());
+    };
+    let id = host_id.parse::<cpal::HostId>()
+        .map_err(|_| AudioError::HostUnavailable(format!("host is not compiled in: {}", host_id)))?;
+    cpal::host_from_id(id)
+        .map_err(|_| AudioError::HostUnavailable(format!("host is not available: {}", host_id)))
+}
+
+// JACKのホストはjackフィーチャーを有効にしたLinux向けビルドでのみ使える
+fn is_jack_host(host_id: cpal::HostId) -> bool {
+    host_id.name().eq_ignore_ascii_case("jack")
 }
 
-trait Coords {
-    fn distance(&self, other: Self) -> f32;
+// JACKのバッファサイズはサーバー側で決まるので、指定されても無視する。無視したときはその理由も返す
+fn resolve_buffer_size(host_id: cpal::HostId, buffer_size: Option<u32>) -> (cpal::BufferSize, Option<String>) {
+    match buffer_size {
+        Some(frames) if is_jack_host(host_id) => (
+            cpal::BufferSize::Default,
+            Some(format!("buffer size {} frames is ignored; JACK uses the server's buffer size", frames)),
+        ),
+        Some(frames) => (cpal::BufferSize::Fixed(frames), None),
+        None => (cpal::BufferSize::Default, None),
+    }
+}
+
+#[tauri::command]
+fn list_hosts() -> Vec<String> {
+    cpal::available_hosts().iter().map(|id| id.to_string()).collect()
 }
 
-impl Coords for [f32; 2] {
-    fn distance(&self, other: Self) -> f32 {
-        let dx = self[0] - other[0];
-        let dy = self[1] - other[1];
-        dx.hypot(dy)
+// UIで使えない機能を隠すための、ビルド時の構成情報
+#[tauri::command]
+fn get_capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        hosts: list_hosts(),
+        hrtf: cfg!(feature = "hrtf"),
+        resampling: cfg!(feature = "resampling"),
+        recording: cfg!(feature = "recording"),
+        sample_formats: SUPPORTED_SAMPLE_FORMATS.iter().map(|f| f.to_string()).collect(),
     }
 }
 
+// directionは"input"または"output"。Noneなら全デバイスを返す (duplexはどちらにも含める)
 #[tauri::command]
-fn get_audio_devices() -> Vec<AudioDeviceDescription> {
-    let host = cpal::default_host();
-    let devices = host.devices().expect("failed to find devices");
-    let default_input_id = host.default_input_device().unwrap().id().unwrap();
-    let default_output_id = host.default_output_device().unwrap().id().unwrap();
-    devices.map(|d| {
-        let id = d.id().expect("failed to get a device id");
-        let dd = d.description().expect("failed to get a device description");
-        AudioDeviceDescription {
-            id: id.1.to_owned(),
-            name: dd.name().to_owned(),
-            driver: dd.driver().map(|s| s.to_owned()),
-            direction: dd.direction().to_string().to_lowercase(),
-            is_default: (id == default_input_id) || (id == default_output_id),
-        }
-    }).collect()
+async fn get_audio_devices(
+    state: tauri::State<'_, AppState>,
+    host_id: Option<String>,
+    direction: Option<String>,
+    force: Option<bool>,
+) -> Result<AudioDeviceList, AudioError> {
+    let wanted = parse_direction(direction.as_deref())?;
+    get_host(host_id.as_deref())?;
+    // UIから頻繁に呼ばれるので、短時間はドライバーに問い合わせずに前回の一覧を返す
+    let key = (host_id.clone(), direction);
+    if !force.unwrap_or(false) {
+        if let Some(devices) = state.device_cache.lock().unwrap().get(&key) {
+            return Ok(AudioDeviceList { devices, truncated: false });
+        }
+    }
+    // ドライバの応答待ちでメインスレッドを止めない
+    let in_flight = Arc::clone(&state.enumerating);
+    let (devices, truncated) = tauri::async_runtime::spawn_blocking(move || enumerate_devices(host_id, wanted, &in_flight))
+        .await
+        .map_err(|e| AudioError::HostUnavailable(format!("device enumeration failed: {}", e)))?;
+    if !truncated {
+        state.device_cache.lock().unwrap().insert(key, devices.clone());
+    }
+    Ok(AudioDeviceList { devices, truncated })
+}
+
+fn enumerate_devices(
+    host_id: Option<String>,
+    wanted: Option<cpal::DeviceDirection>,
+    in_flight: &Arc<AtomicBool>,
+) -> (Vec<AudioDeviceDescription>, bool) {
+    let timeout = std::time::Duration::from_millis(ENUMERATION_TIMEOUT_MS);
+    let (devices, truncated) = collect_with_timeout(timeout, in_flight, move |tx| {
+        let Ok(host) = get_host(host_id.as_deref()) else { return; };
+        let Ok(devices) = host.devices() else { return; };
+        let default_input_id = host.default_input_device().and_then(|d| d.id().ok());
+        let default_output_id = host.default_output_device().and_then(|d| d.id().ok());
+        for d in devices {
+            let (Ok(id), Ok(dd)) = (d.id(), d.description()) else { continue; };
+            if !matches_direction(dd.direction(), wanted) {
+                continue;
+            }
+            let is_default = Some(&id) == default_input_id.as_ref() || Some(&id) == default_output_id.as_ref();
+            let sent = tx.send(AudioDeviceDescription {
+                id: id.1.to_owned(),
+                name: dd.name().to_owned(),
+                driver: dd.driver().map(|s| s.to_owned()),
+                direction: dd.direction().to_string().to_lowercase(),
+                is_default,
+            });
+            if sent.is_err() { return; }
+        }
+    });
+    if truncated {
+        log::warn!("Device enumeration timed out after {} ms", ENUMERATION_TIMEOUT_MS);
+    }
+    (devices, truncated)
+}
+
+// 一定間隔でデバイス一覧を取り直し、IDの集合が変わったら通知する (ホットプラグの検出用)
+fn watch_devices(app: tauri::AppHandle) {
+    let mut last_ids: Option<Vec<String>> = None;
+    // 前回の列挙がまだ終わっていなければ、その周期は新しいスレッドを起動せずに見送る
+    let in_flight = Arc::new(AtomicBool::new(false));
+    loop {
+        let (devices, truncated) = enumerate_devices(None, None, &in_flight);
+        // 途中で打ち切られた一覧と比べると、存在するデバイスまで削除扱いになってしまう
+        if !truncated {
+            let ids: Vec<String> = devices.iter().map(|d| d.id.clone()).collect();
+            if let Some(prev) = last_ids.as_ref() {
+                let (added, removed) = diff_device_ids(prev, &ids);
+                if !added.is_empty() || !removed.is_empty() {
+                    app.state::<AppState>().device_cache.lock().unwrap().clear();
+                    let _ = app.emit("devices_changed", DevicesChangedPayload { devices, added, removed });
+                }
+            }
+            last_ids = Some(ids);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(DEVICE_POLL_MS));
+    }
+}
+
+// cpalには共有/排他を選ぶAPIがないので、もともとデバイスを占有するホスト (ASIO) でのみ排他として扱う。
+// それ以外は共有モードに戻し、その理由を返す
+fn resolve_stream_mode(requested: StreamMode, host_name: &str) -> (StreamMode, Option<String>) {
+    match requested {
+        StreamMode::Shared => (StreamMode::Shared, None),
+        StreamMode::Exclusive if host_name.eq_ignore_ascii_case("asio") => (StreamMode::Exclusive, None),
+        StreamMode::Exclusive => (
+            StreamMode::Shared,
+            Some(format!("exclusive mode is not available on {}; using shared mode", host_name)),
+        ),
+    }
+}
+
+// 同じデバイス、あるいはモニター/ループバック入力が選ばれていれば理由を返す
+fn feedback_risk(input_id: &str, output_id: &str, input_device: &cpal::Device) -> Option<String> {
+    feedback_reason(input_id, output_id, input_device.description().ok().as_ref().map(|d| d.name()))
+}
+
+fn feedback_reason(input_id: &str, output_id: &str, input_name: Option<&str>) -> Option<String> {
+    if input_id == output_id {
+        return Some("input and output are the same device".to_owned());
+    }
+    let name = input_name?;
+    let lower = name.to_lowercase();
+    if ["monitor", "loopback", "stereo mix"].iter().any(|k| lower.contains(k)) {
+        return Some(format!("input device looks like a loopback of an output: {}", name));
+    }
+    None
+}
+
+fn diff_device_ids(old: &[String], new: &[String]) -> (Vec<String>, Vec<String>) {
+    let old: std::collections::BTreeSet<&String> = old.iter().collect();
+    let new: std::collections::BTreeSet<&String> = new.iter().collect();
+    (
+        new.difference(&old).map(|&id| id.clone()).collect(),
+        old.difference(&new).map(|&id| id.clone()).collect(),
+    )
+}
+
+// 別スレッドで列挙し、期限までに届いた分だけ返す (応答しないドライバがあってもUIを止めない)。
+// 期限切れのスレッドは止められないので、in_flightが立っている間は新しく起動せず空の打ち切り扱いにする
+fn collect_with_timeout<T, F>(timeout: std::time::Duration, in_flight: &Arc<AtomicBool>, enumerate: F) -> (Vec<T>, bool)
+where
+    T: Send + 'static,
+    F: FnOnce(std::sync::mpsc::Sender<T>) + Send + 'static,
+{
+    if in_flight.swap(true, Ordering::AcqRel) {
+        log::warn!("Previous device enumeration is still running");
+        return (Vec::new(), true);
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let in_flight = Arc::clone(in_flight);
+    std::thread::spawn(move || {
+        enumerate(tx);
+        in_flight.store(false, Ordering::Release);
+    });
+    let deadline = std::time::Instant::now() + timeout;
+    let mut items = Vec::new();
+    loop {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(item) => items.push(item),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return (items, false),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => return (items, true),
+        }
+    }
+}
+
+fn parse_direction(direction: Option<&str>) -> Result<Option<cpal::DeviceDirection>, AudioError> {
+    match direction {
+        None => Ok(None),
+        Some("input") => Ok(Some(cpal::DeviceDirection::Input)),
+        Some("output") => Ok(Some(cpal::DeviceDirection::Output)),
+        Some(other) => Err(AudioError::InvalidParameter(format!("unknown direction: {}", other))),
+    }
+}
+
+fn matches_direction(dir: cpal::DeviceDirection, wanted: Option<cpal::DeviceDirection>) -> bool {
+    match wanted {
+        None => true,
+        Some(wanted) => dir == wanted || dir == cpal::DeviceDirection::Duplex,
+    }
 }
 
 #[tauri::command]
 fn set_audio_devices(
     window: tauri::Window,
     state: tauri::State<'_, AppState>,
+    host_id: Option<String>,
     input_id: &str,
     output_id: &str,
     latency: usize,
-    position: PositionCoords,
+    mut position: PositionCoords,
     master_gain: f32,
     attenuation: f32,
     lowpass_cutoff_min: f32,
@@ -102,191 +603,2178 @@ fn set_audio_devices(
     lowshelf_gain: f32,
     wet_dry: f32,
     temperature: f32,
-) -> Result<(), ()> {
-    let host = cpal::default_host();
+    temperature_unit: Option<String>,
+    limiter: Option<OutputLimiter>,
+    dc_block: Option<bool>,
+    interpolation: Option<Interpolation>,
+    mode: Option<RenderMode>,
+    gain_trim_l: Option<f32>,
+    gain_trim_r: Option<f32>,
+    humidity: Option<f32>,
+    allpass_order: Option<usize>,
+    auto_gain: Option<bool>,
+    shadow_stages: Option<usize>,
+    crossover_hz: Option<f32>,
+    spectrum_size: Option<usize>,
+    output_channels: Option<[usize; 2]>,
+    ceiling: Option<f32>,
+    width: Option<f32>,
+    invert_l: Option<bool>,
+    invert_r: Option<bool>,
+    cancel_order: Option<usize>,
+    distance_exponent: Option<f32>,
+    phase_align_l: Option<AllPassParams>,
+    phase_align_r: Option<AllPassParams>,
+    shadow_cutoff_lr: Option<f32>,
+    shadow_cutoff_rl: Option<f32>,
+    delay_override: Option<[f64; 2]>,
+    hrir: Option<HrirParams>,
+    lowpass_cutoff_max: Option<f32>,
+    allpass_poles: Option<(Vec<f64>, Vec<f64>)>,
+    fade_in_ms: Option<f32>,
+    allow_feedback: Option<bool>,
+    dither: Option<bool>,
+    stream_mode: Option<StreamMode>,
+    block_size: Option<usize>,
+    dry_lowpass_cutoff: Option<f32>,
+    adaptive_latency: Option<bool>,
+    amp_factor_min: Option<f32>,
+    amp_factor_max: Option<f32>,
+    prime_fill: Option<f32>,
+    output_format: Option<OutputFormat>,
+    room_reflections: Option<RoomParams>,
+    speaker_eq: Option<SpeakerEq>,
+    swap_output_channels: Option<bool>,
+    solo: Option<SoloMode>,
+    null_test: Option<bool>,
+    channel_shadow_stages: Option<[usize; 2]>,
+    allpass_bypass: Option<bool>,
+    emphasis: Option<f32>,
+    gain_stage: Option<GainStage>,
+    tilt_db_per_octave: Option<f32>,
+    mono_compat: Option<bool>,
+    buffer_size: Option<u32>,
+) -> Result<(), AudioError> {
+    for (name, value) in [
+        ("masterGain", master_gain),
+        ("attenuation", attenuation),
+        ("lowpassCutoffMin", lowpass_cutoff_min),
+        ("lowpassCutoffMax", lowpass_cutoff_max.unwrap_or(5000.0)),
+        ("fadeInMs", fade_in_ms.unwrap_or(FADE_IN_MS)),
+        ("highpassCutoff", highpass_cutoff),
+        ("lowshelfCutoff", lowshelf_cutoff),
+        ("lowshelfGain", lowshelf_gain),
+        ("wetDry", wet_dry),
+        ("temperature", temperature),
+        ("gainTrimL", gain_trim_l.unwrap_or(0.0)),
+        ("gainTrimR", gain_trim_r.unwrap_or(0.0)),
+        ("humidity", humidity.unwrap_or(0.0)),
+        ("crossoverHz", crossover_hz.unwrap_or(0.0)),
+        ("dryLowpassCutoff", dry_lowpass_cutoff.unwrap_or(0.0)),
+        ("ceiling", ceiling.unwrap_or(1.0)),
+        ("width", width.unwrap_or(1.0)),
+        ("distanceExponent", distance_exponent.unwrap_or(1.2)),
+        ("shadowCutoffLr", shadow_cutoff_lr.unwrap_or(0.0)),
+        ("shadowCutoffRl", shadow_cutoff_rl.unwrap_or(0.0)),
+    ] {
+        ensure_finite(name, value)?;
+    }
+    ensure_position(&mut position)?;
+    let (master_gain, attenuation, wet_dry) = clamp_mix_params(master_gain, attenuation, wet_dry);
+    let width = width.unwrap_or(1.0).max(0.0);
+    let fade_in_ms = fade_in_ms.unwrap_or(FADE_IN_MS).max(0.0);
+    let distance_exponent = distance_exponent.unwrap_or_else(default_distance_exponent).max(0.0);
+    let amp_factor_min = amp_factor_min.unwrap_or(0.0);
+    let amp_factor_max = amp_factor_max.unwrap_or_else(default_amp_factor_max);
+    ensure_amp_factor_range(amp_factor_min, amp_factor_max)?;
+    let ceiling = ensure_ceiling(ceiling.unwrap_or_else(default_ceiling))?;
+
+    let temperature = to_celsius(temperature, temperature_unit.as_deref().unwrap_or("c"))?;
+    let allpass_order = allpass_order.unwrap_or_else(default_allpass_order);
+    ensure_allpass_order(allpass_order)?;
+    if let Some((a, b)) = allpass_poles.as_ref() {
+        ensure_allpass_poles(a, b)?;
+    }
+    let shadow_stages = shadow_stages.unwrap_or_else(default_shadow_stages);
+    ensure_shadow_stages(shadow_stages)?;
+    for stages in channel_shadow_stages.into_iter().flatten() {
+        ensure_shadow_stages(stages)?;
+    }
+    let cancel_order = cancel_order.unwrap_or_else(default_cancel_order);
+    ensure_cancel_order(cancel_order)?;
+    if let Some(size) = spectrum_size {
+        ensure_spectrum_size(size)?;
+    }
+    let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    ensure_block_size(block_size)?;
+    if let Some(db) = emphasis {
+        ensure_emphasis(db)?;
+    }
+    let tilt_db_per_octave = tilt_db_per_octave.unwrap_or(0.0);
+    ensure_tilt(tilt_db_per_octave)?;
+    if let Some(fill) = prime_fill {
+        ensure_prime_fill(fill)?;
+    }
+    if let Some(room) = room_reflections.as_ref() {
+        ensure_room(room)?;
+    }
+    if let Some(eq) = speaker_eq.as_ref() {
+        ensure_speaker_eq(eq)?;
+    }
+
+    for p in phase_align_l.iter().chain(phase_align_r.iter()) {
+        ensure_all_pass(p)?;
+    }
+    if [shadow_cutoff_lr, shadow_cutoff_rl].into_iter().flatten().any(|c| c <= 0.0) {
+        return Err(AudioError::InvalidParameter("shadow cutoffs must be positive".to_owned()));
+    }
+    if let Some(delays) = delay_override {
+        ensure_delay_override(delays)?;
+    }
+    if let (Some(RenderMode::Hrtf), None) = (mode, hrir.as_ref()) {
+        return Err(AudioError::InvalidParameter("hrtf mode requires hrir files".to_owned()));
+    }
+
+    let host = get_host(host_id.as_deref())?;
+    let requested_mode = stream_mode.unwrap_or_default();
+    if let (actual, Some(reason)) = resolve_stream_mode(requested_mode, host.id().name()) {
+        log::warn!("{}", reason);
+        let _ = window.emit("stream_mode", StreamModePayload { requested: requested_mode, actual, reason });
+    }
     let input_device_id = &cpal::DeviceId(host.id(), input_id.to_owned());
     let output_device_id = &cpal::DeviceId(host.id(), output_id.to_owned());
-    let input_device = host.device_by_id(input_device_id).expect("Failed to find an output device");
-    let output_device = host.device_by_id(output_device_id).expect("Failed to find an output device");
-    let config = input_device.default_input_config().unwrap();
+    let input_device = host.device_by_id(input_device_id)
+        .ok_or_else(|| AudioError::DeviceNotFound(format!("input device not found: {}", input_id)))?;
+    let output_device = host.device_by_id(output_device_id)
+        .ok_or_else(|| AudioError::DeviceNotFound(format!("output device not found: {}", output_id)))?;
+    // 出力が入力に回り込むと再帰的な打ち消しが発振するので、明示的に許可されない限り開始しない
+    if let Some(reason) = feedback_risk(input_id, output_id, &input_device) {
+        let _ = window.emit("feedback_risk", FeedbackRiskPayload { reason: reason.clone() });
+        if !allow_feedback.unwrap_or(false) {
+            return Err(AudioError::InvalidParameter(reason));
+        }
+        log::warn!("Starting despite feedback risk: {}", reason);
+    }
+    let config = input_device.default_input_config()
+        .map_err(|e| AudioError::StreamFailed(format!("no usable input configuration: {}", e)))?;
+    if buffer_size == Some(0) {
+        return Err(AudioError::InvalidParameter("bufferSize must be at least 1".to_owned()));
+    }
+    let (buffer_size, ignored) = resolve_buffer_size(host.id(), buffer_size);
+    if let Some(reason) = ignored {
+        log::warn!("{}", reason);
+    }
+    let stream_config = cpal::StreamConfig { buffer_size, ..config.config() };
+    // リングバッファに1回分のコールバックも入らないレイテンシでは必ずアンダーランするので開始しない
+    if let cpal::BufferSize::Fixed(frames) = buffer_size {
+        match latency_issue(latency, frames, config.sample_rate(), config.channels()) {
+            Some(ConfigIssue { severity: IssueSeverity::Error, message }) => return Err(AudioError::InvalidParameter(message)),
+            Some(ConfigIssue { message, .. }) => log::warn!("{}", message),
+            None => {}
+        }
+    }
+    // 出力デバイスが入力と同じレートに対応していなければ、出力側の既定レートに変換する
+    let output_supports_input_rate = output_device.supported_output_configs()
+        .map(|mut configs| configs.any(|c| (c.min_sample_rate()..=c.max_sample_rate()).contains(&config.sample_rate())))
+        .unwrap_or(false);
+    if !output_supports_input_rate && !cfg!(feature = "resampling") {
+        return Err(AudioError::UnsupportedConfig(format!("output device does not support {} Hz and this build has no resampling", config.sample_rate())));
+    }
+    let output_sample_rate = if output_supports_input_rate {
+        config.sample_rate()
+    } else {
+        output_device.default_output_config().map(|c| c.sample_rate()).unwrap_or(config.sample_rate())
+    };
+    // マッピングが無ければ従来どおり2チャンネルで出力する。モノラルのデバイスにはダウンミックスして出す
+    let device_channels = output_device.default_output_config().map(|c| c.channels()).unwrap_or(2);
+    let output_channel_count = match output_channels {
+        Some(_) => device_channels,
+        None => device_channels.min(2),
+    };
+    let output_channels = output_channels.unwrap_or([0, 1]);
+    if output_channel_count > 1 {
+        ensure_output_channels(output_channels, output_channel_count)?;
+    }
 
+    // 前のルーティングが残っていると同じデバイスを二重に開くので、止めて終わるのを待ってから始める
+    stop_routing(&state);
     state.abort_signal.store(false, Ordering::Relaxed);
+    state.pause_signal.store(false, Ordering::Relaxed);
     let should_abort = Arc::clone(&state.abort_signal);
+    let should_pause = Arc::clone(&state.pause_signal);
+    let running = Arc::clone(&state.running);
+    running.store(true, Ordering::Relaxed);
+    state.gain_signal.store(master_gain.to_bits(), Ordering::Relaxed);
+    let gain_signal = Arc::clone(&state.gain_signal);
+    state.bypass_signal.store(false, Ordering::Relaxed);
+    let bypass_signal = Arc::clone(&state.bypass_signal);
+    state.freeze_signal.store(false, Ordering::Relaxed);
+    let freeze_signal = Arc::clone(&state.freeze_signal);
+    *state.eq_update.lock().unwrap() = None;
+    let eq_update = Arc::clone(&state.eq_update);
+    *state.filter_update.lock().unwrap() = None;
+    let filter_update = Arc::clone(&state.filter_update);
+    *state.output_switch.lock().unwrap() = None;
+    let output_switch = Arc::clone(&state.output_switch);
 
-    let _handler = std::thread::spawn(move || {
+    let handler = std::thread::spawn(move || {
         let thru_opt = ThruOpt {
+            window: &window,
             input: &input_device,
             output: &output_device,
-            config: &config.to_owned().into(),
+            config: &stream_config,
+            output_sample_rate,
             latency,
-            position,
             master_gain,
-            attenuation,
-            lowpass_cutoff_min,
-            highpass_cutoff,
-            lowshelf_cutoff,
-            lowshelf_gain,
             wet_dry,
-            temperature,
+            width,
+            fade_in_ms,
+            dither: dither.unwrap_or(false),
+            block_size,
+            auto_gain: auto_gain.unwrap_or(false),
+            crossover_hz,
+            dry_lowpass_cutoff,
+            adaptive_latency: adaptive_latency.unwrap_or(false),
+            prime_fill,
+            output_format: output_format.unwrap_or_default(),
+            gain_stage: gain_stage.unwrap_or_default(),
+            room_reflections,
+            freeze_signal,
+            eq_update,
+            swap_output_channels: swap_output_channels.unwrap_or(false),
+            null_test: null_test.unwrap_or(false),
+            mono_compat: mono_compat.unwrap_or(false),
+            spectrum_size,
+            output_channels,
+            output_channel_count,
+            engine: EngineParams {
+                position,
+                attenuation,
+                lowpass_cutoff_min,
+                lowpass_cutoff_max: lowpass_cutoff_max.unwrap_or_else(default_lowpass_cutoff_max),
+                highpass_cutoff,
+                lowshelf_cutoff,
+                lowshelf_gain,
+                temperature,
+                humidity,
+                limiter: limiter.unwrap_or_default(),
+                ceiling,
+                dc_block: dc_block.unwrap_or(false),
+                interpolation: interpolation.unwrap_or_default(),
+                mode: mode.unwrap_or_default(),
+                solo: solo.unwrap_or_default(),
+                allpass_bypass: allpass_bypass.unwrap_or(false),
+                emphasis,
+                tilt_db_per_octave,
+                gain_trims: [gain_trim_l.unwrap_or(0.0), gain_trim_r.unwrap_or(0.0)],
+                invert_l: invert_l.unwrap_or(false),
+                invert_r: invert_r.unwrap_or(false),
+                allpass_order,
+                allpass_poles,
+                shadow_stages,
+                channel_shadow_stages,
+                cancel_order,
+                distance_exponent,
+                amp_factor_min,
+                amp_factor_max,
+                phase_align_l,
+                phase_align_r,
+                shadow_cutoff_lr,
+                shadow_cutoff_rl,
+                delay_override,
+                hrir,
+                speaker_eq,
+            },
         };
-        match config.sample_format() {
-            cpal::SampleFormat::F32 => start_thru::<f32>(thru_opt, should_abort).unwrap(),
-            cpal::SampleFormat::I16 => start_thru::<i16>(thru_opt, should_abort).unwrap(),
-            cpal::SampleFormat::U16 => start_thru::<u16>(thru_opt, should_abort).unwrap(),
-            _ => panic!("sample format is invalid")
-        }
-        window.emit("finished", Payload { is_finished: true }).unwrap();
+        let result = match config.sample_format() {
+            cpal::SampleFormat::F32 => start_thru::<f32>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::F64 => start_thru::<f64>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::I8 => start_thru::<i8>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::I16 => start_thru::<i16>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::I24 => start_thru::<cpal::I24>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::I32 => start_thru::<i32>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::I64 => start_thru::<i64>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::U8 => start_thru::<u8>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::U16 => start_thru::<u16>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::U24 => start_thru::<cpal::U24>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::U32 => start_thru::<u32>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            cpal::SampleFormat::U64 => start_thru::<u64>(thru_opt, should_abort, should_pause, gain_signal, bypass_signal, filter_update, output_switch),
+            f => Err(AudioError::UnsupportedConfig(format!("unsupported sample format: {}", f))),
+        };
+        running.store(false, Ordering::Relaxed);
+        report_finished(&window, "Audio routing failed", result);
     });
+    *state.routing_thread.lock().unwrap() = Some(handler);
 
     Ok(())
 }
 
-#[tauri::command]
-fn abort_audio_routing(state: tauri::State<'_, AppState>) -> Result<(), ()> {
+// 失敗しても必ずfinishedを送って、UIが待ち続けないようにする。
+// 終了処理中はウィンドウが閉じていて送れないことがあるので、送信の失敗は無視する
+fn report_finished<R: tauri::Runtime>(emitter: &impl Emitter<R>, context: &str, result: Result<(), AudioError>) {
+    if let Err(e) = result {
+        log::error!("{}: {:?}", context, e);
+        let _ = emitter.emit("error", e);
+    }
+    let _ = emitter.emit("finished", Payload { is_finished: true });
+}
+
+// ストリームを確実に閉じて、デバイスを解放する
+fn stop_routing(state: &AppState) {
     state.abort_signal.store(true, Ordering::Relaxed);
+    let handler = state.routing_thread.lock().unwrap().take();
+    if let Some(handler) = handler {
+        if handler.join().is_err() {
+            log::error!("Audio routing thread panicked");
+        }
+    }
+}
+
+#[tauri::command]
+fn play_test_tone(
+    window: tauri::Window,
+    state: tauri::State<'_, AppState>,
+    host_id: Option<String>,
+    output_id: &str,
+    tone: TestTone,
+    duration_ms: u64,
+) -> Result<(), AudioError> {
+    if let TestTone::Sine { freq } = tone {
+        if !(freq.is_finite() && freq > 0.0) {
+            return Err(AudioError::InvalidParameter(format!("invalid tone frequency: {}", freq)));
+        }
+    }
+    let host = get_host(host_id.as_deref())?;
+    let output_device = host.device_by_id(&cpal::DeviceId(host.id(), output_id.to_owned()))
+        .ok_or_else(|| AudioError::DeviceNotFound(format!("output device not found: {}", output_id)))?;
+    let config = output_device.default_output_config()
+        .map_err(|e| AudioError::StreamFailed(format!("no usable output configuration: {}", e)))?;
+    ensure_sample_format(config.sample_format())?;
+
+    state.abort_signal.store(false, Ordering::Relaxed);
+    let should_abort = Arc::clone(&state.abort_signal);
+    let _handler = std::thread::spawn(move || {
+        let output = ToneOutput { device: &output_device, config: config.to_owned().into(), format: config.sample_format() };
+        let duration = std::time::Duration::from_millis(duration_ms);
+        let result = play_tone(&output, tone, duration, should_abort, |x| x, || {});
+        report_finished(&window, "Failed to play test tone", result);
+    });
+
     Ok(())
 }
 
-fn start_thru<T>(opt: ThruOpt<'_>, abort_signal: Arc<AtomicBool>) -> Result<(), ()>
+// テスト信号を鳴らす出力ストリームの設定
+struct ToneOutput<'a> {
+    device: &'a cpal::Device,
+    config: cpal::StreamConfig,
+    format: cpal::SampleFormat,
+}
+
+// test_tone::playをデバイスのサンプルフォーマットに合わせて呼び分ける
+fn play_tone<P, M>(
+    output: &ToneOutput,
+    tone: TestTone,
+    duration: std::time::Duration,
+    abort_signal: Arc<AtomicBool>,
+    process: P,
+    on_tick: M,
+) -> Result<(), AudioError>
 where
-    T: cpal::SizedSample + FromSample<f32> + Send + 'static,
-    f32: cpal::FromSample<T>,
+    P: FnMut([f32; 2]) -> [f32; 2] + Send + 'static,
+    M: FnMut(),
 {
-    let sample_rate = opt.config.sample_rate as f32;
-    let channels = opt.config.channels as usize;
-    
-    let latency_frames = opt.latency * (sample_rate as usize) / 1000;
-    let latency_samples = latency_frames * channels;
+    let ToneOutput { device, config, format } = output;
+    match *format {
+        cpal::SampleFormat::F32 => test_tone::play::<f32, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::F64 => test_tone::play::<f64, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::I8 => test_tone::play::<i8, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::I16 => test_tone::play::<i16, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::I24 => test_tone::play::<cpal::I24, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::I32 => test_tone::play::<i32, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::I64 => test_tone::play::<i64, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::U8 => test_tone::play::<u8, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::U16 => test_tone::play::<u16, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::U24 => test_tone::play::<cpal::U24, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::U32 => test_tone::play::<u32, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        cpal::SampleFormat::U64 => test_tone::play::<u64, _, _>(device, config, tone, duration, abort_signal, process, on_tick),
+        f => Err(AudioError::UnsupportedFormat(format!("unsupported sample format: {}", f))),
+    }
+}
 
-    let rb = ringbuf::HeapRb::<f32>::from(vec![0.0.to_sample::<f32>(); latency_samples]);
-    let (mut prod, mut cons) = rb.split();
+fn ensure_sample_format(format: cpal::SampleFormat) -> Result<(), AudioError> {
+    if SUPPORTED_SAMPLE_FORMATS.contains(&format) {
+        Ok(())
+    } else {
+        Err(AudioError::UnsupportedFormat(format!("unsupported sample format: {}", format)))
+    }
+}
 
-    let abort_signal_input = Arc::clone(&abort_signal);
-    let input_fn = move |data: &[T], _: &cpal::InputCallbackInfo| {
-        for &sample in data {
-            if prod.try_push(sample.to_sample::<f32>()).is_err() {
-                eprintln!("Output stream fell behind; increase latency");
-                abort_signal_input.store(true, Ordering::Relaxed);
-                break;
-            }
-        }
+// ストリームは作らずに、開始前に分かる問題を列挙する
+#[tauri::command]
+fn validate_config(
+    host_id: Option<String>,
+    input_id: &str,
+    output_id: &str,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+    latency: Option<usize>,
+) -> Result<Vec<ConfigIssue>, AudioError> {
+    let host = get_host(host_id.as_deref())?;
+    let mut issues = Vec::new();
+    let error = |message: String| ConfigIssue { severity: IssueSeverity::Error, message };
+    let warning = |message: String| ConfigIssue { severity: IssueSeverity::Warning, message };
+    let (resolved, ignored) = resolve_buffer_size(host.id(), buffer_size);
+    if let Some(reason) = ignored {
+        log::warn!("{}", reason);
+        issues.push(warning(reason));
+    }
+    let buffer_size = match resolved {
+        cpal::BufferSize::Fixed(frames) => Some(frames),
+        cpal::BufferSize::Default => None,
     };
 
-    let distances = calc_distance(&opt.position);
-    let min_distance = distances.into_iter().reduce(f32::min).unwrap();
-    let amp_factors = distances.map(|d| (min_distance / d).powf(1.2) as f64);
-    let [main_delays, ct_delays] = calc_delay_frames(
-        sample_rate as f32,
-        distances,
-        calc_speed_of_sound(opt.temperature)
-    );
-    println!("Delay L/R are {}/{} frames.", ct_delays[0], ct_delays[1]);
-
-    let listenr_pos: [f32; 2] = opt.position.left_ear.iter().zip(opt.position.right_ear).map(|(a, b)| a + b).collect::<Vec<f32>>().try_into().unwrap();
-    let shadow_cutoff_l = calc_shadow_cutoff(listenr_pos, opt.position.left_speaker, opt.lowpass_cutoff_min);
-    let shadow_cutoff_r = calc_shadow_cutoff(listenr_pos, opt.position.right_speaker, opt.lowpass_cutoff_min);
-
-    let mut engine = CtcEngine::new(
-        sample_rate,
-        ct_delays,
-        main_delays,
-        [shadow_cutoff_l, shadow_cutoff_r],
-        opt.highpass_cutoff,
-        opt.lowshelf_cutoff,
-        opt.lowshelf_gain,
-    );
-
-    let mut ctc_sig = signal::from_iter(std::iter::from_fn(move || {
-        if cons.occupied_len() < 2 { return None; }
-        let l = cons.try_pop()? * opt.master_gain;
-        let r  = cons.try_pop()? * opt.master_gain;
-        Some([l, r])
-    })).map(move |[l, r]| {
-        let [out_l, out_r] = engine.process([l, r], opt.attenuation as f64, &amp_factors);
-        let w = &opt.wet_dry;
-        let d = 1.0 - &opt.wet_dry;
-        [ out_l * w + l * d, out_r * w + r * d ]
-    });
-
-    let output_fn = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-        for sample in data.chunks_exact_mut(2) {
-            let sig = ctc_sig.next();
-            if let Some(l) = sample.get_mut(0) {
-                *l = sig[0].to_sample();
-            }
-            if let Some(r) = sample.get_mut(1) {
-                *r = sig[1].to_sample();
-            }
-        }
+    let input_device = host.device_by_id(&cpal::DeviceId(host.id(), input_id.to_owned()));
+    let output_device = host.device_by_id(&cpal::DeviceId(host.id(), output_id.to_owned()));
+    if input_device.is_none() {
+        issues.push(error(format!("input device not found: {}", input_id)));
+    }
+    if output_device.is_none() {
+        issues.push(error(format!("output device not found: {}", output_id)));
+    }
+    let (Some(input_device), Some(output_device)) = (input_device, output_device) else {
+        return Ok(issues);
     };
 
-    let err_fn = |e: cpal::StreamError| {
-        eprintln!("Stream error occured: {:?}", e);
+    let Ok(default_config) = input_device.default_input_config() else {
+        issues.push(error("input device has no usable configuration".to_owned()));
+        return Ok(issues);
     };
+    let sample_rate = sample_rate.unwrap_or(default_config.sample_rate());
 
-    let input_stream = opt.input.build_input_stream(&opt.config, input_fn, err_fn, None).expect("Failed to build input stream");
-    let output_stream = opt.output.build_output_stream(&opt.config, output_fn, err_fn, None).expect("Failed to build output stream");
-
-    println!("Started streams with {} ms of latency.", &opt.latency);
-    input_stream.play().expect("Failed to play input stream");
-    output_stream.play().expect("Failed to play output stream.");
+    let input_ok = input_device.supported_input_configs()
+        .map(|mut configs| configs.any(|c| supports_config(&c, sample_rate, buffer_size)))
+        .unwrap_or(false);
+    if !input_ok {
+        issues.push(error(format!("input device does not support {} Hz with the requested buffer size", sample_rate)));
+    }
+    let output_configs: Vec<cpal::SupportedStreamConfigRange> = output_device.supported_output_configs()
+        .map(|configs| configs.collect())
+        .unwrap_or_default();
+    issues.extend(output_config_issue(&output_configs, sample_rate, buffer_size));
+    if let Some(reason) = feedback_risk(input_id, output_id, &input_device) {
+        issues.push(warning(reason));
+    }
+    if default_config.channels() > 2 {
+        issues.push(warning(format!("input device has {} channels; only the first two are used", default_config.channels())));
+    }
 
-    let dur = std::time::Duration::from_millis(opt.latency as u64);
-    while !abort_signal.load(Ordering::Relaxed) {
-        std::thread::sleep(dur);
+    if let (Some(latency), Some(frames)) = (latency, buffer_size) {
+        issues.extend(latency_issue(latency, frames, sample_rate, default_config.channels()));
     }
+    Ok(issues)
+}
 
-    drop(input_stream);
-    drop(output_stream);
-    
-    println!("Closed safely!");
-    Ok(())
+fn supports_config(range: &cpal::SupportedStreamConfigRange, sample_rate: u32, buffer_size: Option<u32>) -> bool {
+    (range.min_sample_rate()..=range.max_sample_rate()).contains(&sample_rate)
+        && match (range.buffer_size(), buffer_size) {
+            (cpal::SupportedBufferSize::Range { min, max }, Some(frames)) => (*min..=*max).contains(&frames),
+            _ => true,
+        }
 }
 
-fn calc_distance(pos: &PositionCoords) -> [f32; 4] {
-    [
-        pos.left_speaker.distance(pos.left_ear),
-        pos.left_speaker.distance(pos.right_ear),
-        pos.right_speaker.distance(pos.left_ear),
-        pos.right_speaker.distance(pos.right_ear),
-    ]
+fn output_config_issue(configs: &[cpal::SupportedStreamConfigRange], sample_rate: u32, buffer_size: Option<u32>) -> Option<ConfigIssue> {
+    let error = |message: String| ConfigIssue { severity: IssueSeverity::Error, message };
+    if !configs.iter().any(|c| supports_config(c, sample_rate, buffer_size)) {
+        Some(error(format!("output device does not support {} Hz with the requested buffer size", sample_rate)))
+    } else if !configs.iter().any(|c| supports_config(c, sample_rate, buffer_size) && c.channels() >= 2) {
+        Some(error("output device does not support a stereo configuration".to_owned()))
+    } else {
+        None
+    }
 }
 
-fn calc_delay_frames(sample_rate: f32, distances: [f32; 4], speed_of_sound: f64) -> [[f64; 2]; 2] {
-    let k = sample_rate as f64 / speed_of_sound;
-    let [ls2le, ls2re, rs2le, rs2re] = distances.map(|d| d as f64 * k);
-    let main_delays = if ls2le > rs2re { [ 0.0, ls2le - rs2re ] } else { [ rs2re - ls2le, 0.0 ] };
-    [
-        main_delays,
-        [ 1.0f64.max((rs2le - ls2le).abs()), 1.0f64.max((ls2re - rs2re).abs()) ]
-    ]
+// リングバッファはlatency分の容量しかないので、1回のコールバックで溢れないか確認する
+fn latency_issue(latency: usize, frames: u32, sample_rate: u32, channels: u16) -> Option<ConfigIssue> {
+    let latency_frames = latency * sample_rate as usize / 1000;
+    if latency_frames < frames as usize {
+        Some(ConfigIssue {
+            severity: IssueSeverity::Error,
+            message: format!("latency {} ms is shorter than the buffer size ({} frames)", latency, frames),
+        })
+    } else if latency < latency::recommended_latency_ms(sample_rate, frames, channels) {
+        Some(ConfigIssue {
+            severity: IssueSeverity::Warning,
+            message: format!("latency {} ms leaves less than two buffers of headroom", latency),
+        })
+    } else {
+        None
+    }
 }
 
-fn calc_speed_of_sound(t_c: f32) -> f64 {
-    let t_k = 273.15 + t_c;
-    (1.403 * 8.314462 * t_k as f64 / 28.966e-3).sqrt()
+#[tauri::command]
+fn save_last_settings(app: tauri::AppHandle, state: tauri::State<'_, AppState>, settings: PresetParams) -> Result<(), AudioError> {
+    *state.last_settings.lock().unwrap() = Some(settings.clone());
+    settings::save_last(&app_config_dir(&app)?, &settings).map_err(AudioError::Io)
 }
 
-fn calc_shadow_cutoff(coord1: [f32; 2], coord2: [f32; 2], cutoff_min: f32) -> f32 {
-    let cutoff_max = 5000.0;
-    let diff: Vec<f32> = coord1.iter().zip(coord2).map(|(a, b)| a - b).collect();
-    let theta = diff[1].atan2(diff[0]).abs();
-    cutoff_min + (cutoff_max - cutoff_min) * theta.cos().powi(2)
+// 停止時と終了時に呼ぶ。ウィンドウを閉じる間際の保存が間に合わなかったときのために、受け取っている最新の設定を書き出す
+fn flush_last_settings<R: tauri::Runtime>(app: &tauri::AppHandle<R>) {
+    let Some(settings) = app.state::<AppState>().last_settings.lock().unwrap().take() else { return };
+    if let Err(e) = app_config_dir(app).and_then(|dir| settings::save_last(&dir, &settings).map_err(AudioError::Io)) {
+        log::warn!("Failed to save last settings: {:?}", e);
+    }
+}
+
+#[tauri::command]
+fn load_last_settings(app: tauri::AppHandle) -> Result<Option<PresetParams>, AudioError> {
+    settings::load_last(&app_config_dir(&app)?).map_err(AudioError::Io)
+}
+
+fn app_config_dir<R: tauri::Runtime>(app: &tauri::AppHandle<R>) -> Result<std::path::PathBuf, AudioError> {
+    app.path().app_config_dir().map_err(|e| AudioError::Io(format!("no config directory: {}", e)))
+}
+
+#[tauri::command]
+fn abort_audio_routing<R: tauri::Runtime>(app: tauri::AppHandle<R>, state: tauri::State<'_, AppState>) -> Result<(), ()> {
+    state.abort_signal.store(true, Ordering::Relaxed);
+    flush_last_settings(&app);
+    Ok(())
+}
+
+// UIの再読み込み後に開始/停止ボタンの状態を合わせるため、停止要求が出ていない実行中のルーティングがあるかを返す
+#[tauri::command]
+fn is_routing_active(state: tauri::State<'_, AppState>) -> bool {
+    state.running.load(Ordering::Relaxed) && !state.abort_signal.load(Ordering::Relaxed)
+}
+
+#[tauri::command]
+fn pause_audio_routing(state: tauri::State<'_, AppState>) -> Result<(), ()> {
+    state.set_paused(true);
+    Ok(())
+}
+
+#[tauri::command]
+fn resume_audio_routing(state: tauri::State<'_, AppState>) -> Result<(), ()> {
+    state.set_paused(false);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_master_gain(state: tauri::State<'_, AppState>, master_gain: f32) -> Result<(), AudioError> {
+    let master_gain = ensure_finite("masterGain", master_gain)?.max(0.0);
+    state.gain_signal.store(master_gain.to_bits(), Ordering::Relaxed);
+    Ok(())
+}
+
+// スピーカーの補正EQを再生中のエンジンに反映する
+#[tauri::command]
+fn set_speaker_eq(state: tauri::State<'_, AppState>, eq: SpeakerEq) -> Result<(), AudioError> {
+    ensure_speaker_eq(&eq)?;
+    *state.eq_update.lock().unwrap() = Some(eq);
+    Ok(())
+}
+
+// 測定中に係数が変わらないよう、フィルタ係数の更新を止める。設定後の状態を返す
+#[tauri::command]
+fn freeze_filters(state: tauri::State<'_, AppState>, frozen: bool) -> bool {
+    state.freeze_signal.store(frozen, Ordering::Relaxed);
+    frozen
+}
+
+// エンジンは動かしたまま、処理済みの出力と未処理の入力をクロスフェードで切り替える
+#[tauri::command]
+fn set_bypass_ramped(state: tauri::State<'_, AppState>, enable: bool) -> Result<(), ()> {
+    state.bypass_signal.store(enable, Ordering::Relaxed);
+    Ok(())
+}
+
+// 入力ストリームと処理の状態はそのままに、出力デバイスだけをフェードを挟んで切り替える
+#[tauri::command]
+fn switch_output_device(
+    state: tauri::State<'_, AppState>,
+    host_id: Option<String>,
+    new_output_id: &str,
+) -> Result<(), AudioError> {
+    let host = get_host(host_id.as_deref())?;
+    let device_id = cpal::DeviceId(host.id(), new_output_id.to_owned());
+    if host.device_by_id(&device_id).is_none() {
+        return Err(AudioError::DeviceNotFound(format!("output device not found: {}", new_output_id)));
+    }
+    *state.output_switch.lock().unwrap() = Some(device_id);
+    Ok(())
+}
+
+fn ensure_position(position: &mut PositionCoords) -> Result<(), AudioError> {
+    for coord in [position.left_speaker, position.right_speaker, position.left_ear, position.right_ear] {
+        for value in coord {
+            ensure_finite("position", value)?;
+        }
+    }
+    position.head_radius = ensure_finite("headRadius", position.head_radius)?.max(0.0);
+    Ok(())
+}
+
+#[tauri::command]
+fn update_filter_params(state: tauri::State<'_, AppState>, mut params: FilterParams) -> Result<(), AudioError> {
+    ensure_finite("lowpassCutoffMin", params.lowpass_cutoff_min)?;
+    ensure_finite("highpassCutoff", params.highpass_cutoff)?;
+    ensure_finite("lowshelfCutoff", params.lowshelf_cutoff)?;
+    ensure_finite("lowshelfGain", params.lowshelf_gain)?;
+    if let Some(position) = params.position.as_mut() {
+        ensure_position(position)?;
+    }
+    *state.filter_update.lock().unwrap() = Some(params);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_impulse_response(sample_rate: f32, length: usize, params: EngineParams) -> Result<ImpulseResponse, AudioError> {
+    ensure_engine_params(&params)?;
+    transaural_core::impulse_response(sample_rate, length, &params)
+}
+
+// ファイル全体の読み書きと処理を行うので、メインスレッドを止めないように別スレッドで実行する
+#[tauri::command]
+async fn process_file_offline(in_path: String, out_path: String, params: EngineParams) -> Result<(), AudioError> {
+    ensure_engine_params(&params)?;
+    tauri::async_runtime::spawn_blocking(move || transaural_core::process_file_offline(&in_path, &out_path, &params))
+        .await
+        .map_err(|e| AudioError::StreamFailed(format!("offline processing failed: {}", e)))?
+}
+
+// 数秒分の処理を回すので、メインスレッドを止めないように別スレッドで測る
+#[tauri::command]
+async fn benchmark_engine(sample_rate: f32, allpass_order: usize, block_size: usize) -> Result<BenchResult, AudioError> {
+    ensure_sample_rate(sample_rate)?;
+    ensure_allpass_order(allpass_order)?;
+    ensure_block_size(block_size)?;
+    tauri::async_runtime::spawn_blocking(move || transaural_core::benchmark_engine(sample_rate, allpass_order, block_size))
+        .await
+        .map_err(|e| AudioError::StreamFailed(format!("benchmark failed: {}", e)))
+}
+
+#[tauri::command]
+fn get_engine_coefficients(sample_rate: f32, params: EngineParams) -> Result<EngineCoefficients, AudioError> {
+    ensure_engine_params(&params)?;
+    transaural_core::engine_coefficients(sample_rate, &params)
+}
+
+// 処理後に中央に定位するはずのモノラルのピンクノイズを、エンジンを通して鳴らす (聴取位置の調整用)。
+// 再生中は出力の相関をcorrelationイベントで送る
+#[tauri::command]
+fn play_center_check(
+    window: tauri::Window,
+    state: tauri::State<'_, AppState>,
+    host_id: Option<String>,
+    output_id: &str,
+    params: EngineParams,
+    duration_ms: u64,
+) -> Result<(), AudioError> {
+    ensure_engine_params(&params)?;
+    let host = get_host(host_id.as_deref())?;
+    let output_device = host.device_by_id(&cpal::DeviceId(host.id(), output_id.to_owned()))
+        .ok_or_else(|| AudioError::DeviceNotFound(format!("output device not found: {}", output_id)))?;
+    let config = output_device.default_output_config()
+        .map_err(|e| AudioError::StreamFailed(format!("no usable output configuration: {}", e)))?;
+    ensure_sample_format(config.sample_format())?;
+    let sample_rate = config.sample_rate() as f32;
+    let (mut engine, amp_factors, _) = build_engine(sample_rate, &params)?;
+    let attenuation = params.attenuation as f64;
+
+    state.abort_signal.store(false, Ordering::Relaxed);
+    let should_abort = Arc::clone(&state.abort_signal);
+    let _handler = std::thread::spawn(move || {
+        let duration = std::time::Duration::from_millis(duration_ms);
+        let correlation = Arc::new(AtomicU32::new(0.0f32.to_bits()));
+        let correlation_audio = Arc::clone(&correlation);
+        let mut meter = CorrelationMeter::new(sample_rate);
+        let process = move |input: [f32; 2]| {
+            let out = engine.process(input, attenuation, &amp_factors);
+            meter.update(out);
+            correlation_audio.store(meter.value().to_bits(), Ordering::Relaxed);
+            out
+        };
+        let mut last_emit = std::time::Instant::now();
+        let on_tick = || {
+            if last_emit.elapsed().as_millis() as u64 >= SPECTRUM_INTERVAL_MS {
+                let _ = window.emit("correlation", CorrelationPayload { correlation: f32::from_bits(correlation.load(Ordering::Relaxed)) });
+                last_emit = std::time::Instant::now();
+            }
+        };
+        let output = ToneOutput { device: &output_device, config: config.to_owned().into(), format: config.sample_format() };
+        let result = play_tone(&output, TestTone::PinkNoise, duration, should_abort, process, on_tick);
+        report_finished(&window, "Failed to play center check", result);
+    });
+
+    Ok(())
+}
+
+fn ensure_engine_params(params: &EngineParams) -> Result<(), AudioError> {
+    ensure_allpass_order(params.allpass_order)?;
+    if let Some((a, b)) = params.allpass_poles.as_ref() {
+        ensure_allpass_poles(a, b)?;
+    }
+    ensure_shadow_stages(params.shadow_stages)?;
+    for stages in params.channel_shadow_stages.into_iter().flatten() {
+        ensure_shadow_stages(stages)?;
+    }
+    ensure_ceiling(params.ceiling)?;
+    ensure_temperature(params.temperature)?;
+    ensure_cancel_order(params.cancel_order)?;
+    ensure_finite("distanceExponent", params.distance_exponent)?;
+    ensure_finite("lowpassCutoffMax", params.lowpass_cutoff_max)?;
+    ensure_amp_factor_range(params.amp_factor_min, params.amp_factor_max)?;
+    for p in params.phase_align_l.iter().chain(params.phase_align_r.iter()) {
+        ensure_all_pass(p)?;
+    }
+    if let Some(delays) = params.delay_override {
+        ensure_delay_override(delays)?;
+    }
+    if let Some(eq) = params.speaker_eq.as_ref() {
+        ensure_speaker_eq(eq)?;
+    }
+    if let Some(db) = params.emphasis {
+        ensure_emphasis(db)?;
+    }
+    ensure_tilt(params.tilt_db_per_octave)?;
+    Ok(())
+}
+
+// UIでレイテンシを自動入力するための推奨値
+#[tauri::command]
+fn get_recommended_latency(sample_rate: u32, buffer_size: u32, channels: u16) -> usize {
+    latency::recommended_latency_ms(sample_rate, buffer_size, channels)
+}
+
+// 測定中はストリームを開いて待つので、メインスレッドを止めないように別スレッドで行う
+#[tauri::command]
+async fn measure_latency(host_id: Option<String>, input_id: String, output_id: String) -> Result<LatencyMeasurement, AudioError> {
+    tauri::async_runtime::spawn_blocking(move || measure_latency_blocking(host_id.as_deref(), &input_id, &output_id))
+        .await
+        .map_err(|e| AudioError::StreamFailed(format!("latency measurement failed: {}", e)))?
+}
+
+fn measure_latency_blocking(host_id: Option<&str>, input_id: &str, output_id: &str) -> Result<LatencyMeasurement, AudioError> {
+    let host = get_host(host_id)?;
+    let input_device = host.device_by_id(&cpal::DeviceId(host.id(), input_id.to_owned()))
+        .ok_or_else(|| AudioError::DeviceNotFound(format!("input device not found: {}", input_id)))?;
+    let output_device = host.device_by_id(&cpal::DeviceId(host.id(), output_id.to_owned()))
+        .ok_or_else(|| AudioError::DeviceNotFound(format!("output device not found: {}", output_id)))?;
+    let config = input_device.default_input_config()
+        .map_err(|e| AudioError::StreamFailed(format!("no usable input configuration: {}", e)))?;
+    let stream_config: cpal::StreamConfig = config.to_owned().into();
+    let timeout = std::time::Duration::from_millis(LATENCY_TIMEOUT_MS);
+    let samples = match config.sample_format() {
+        cpal::SampleFormat::F32 => latency::measure_round_trip::<f32>(&input_device, &output_device, &stream_config, timeout),
+        cpal::SampleFormat::I16 => latency::measure_round_trip::<i16>(&input_device, &output_device, &stream_config, timeout),
+        cpal::SampleFormat::U16 => latency::measure_round_trip::<u16>(&input_device, &output_device, &stream_config, timeout),
+        f => return Err(AudioError::UnsupportedFormat(format!("unsupported sample format: {}", f))),
+    }?.ok_or_else(|| AudioError::LatencyNotDetected(format!("no click detected within {} ms", LATENCY_TIMEOUT_MS)))?;
+    Ok(LatencyMeasurement {
+        samples,
+        ms: samples as f32 * 1000.0 / stream_config.sample_rate as f32,
+    })
+}
+
+fn start_thru<T>(
+    opt: ThruOpt<'_>,
+    abort_signal: Arc<AtomicBool>,
+    pause_signal: Arc<AtomicBool>,
+    gain_signal: Arc<AtomicU32>,
+    bypass_signal: Arc<AtomicBool>,
+    filter_update: Arc<Mutex<Option<FilterParams>>>,
+    output_switch: Arc<Mutex<Option<cpal::DeviceId>>>,
+) -> Result<(), AudioError>
+where
+    T: cpal::SizedSample + FromSample<f32> + Send + 'static,
+    f32: cpal::FromSample<T>,
+{
+    let input_rate = opt.config.sample_rate;
+    // 処理はすべて出力側のレートで行う (出力デバイスの切り替えで変わることがある)
+    let mut sample_rate = opt.output_sample_rate as f32;
+    let channels = opt.config.channels as usize;
+    
+    let latency_frames = opt.latency * (input_rate as usize) / 1000;
+    let latency_samples = latency_frames * channels;
+
+    // prime_fillを指定したときは空のバッファから始め、その割合まで溜まってから出力を始める。
+    // しきい値に達した直後の入力コールバックで溢れないよう、1回分の余裕を足しておく
+    let rb = match opt.prime_fill {
+        Some(_) => {
+            let callback_frames = match opt.config.buffer_size {
+                cpal::BufferSize::Fixed(frames) => frames as usize,
+                cpal::BufferSize::Default => PRIME_HEADROOM_FRAMES,
+            };
+            ringbuf::HeapRb::<f32>::new(latency_samples + callback_frames * channels)
+        }
+        None => ringbuf::HeapRb::<f32>::from(vec![0.0.to_sample::<f32>(); latency_samples]),
+    };
+    let (mut prod, mut cons) = rb.split();
+    let mut primer = opt.prime_fill.map(|fill| Primer::new((latency_frames as f32 * fill) as usize * channels));
+    // レイテンシの自動調整で差し替えるリングバッファ。各コールバックが次の呼び出しで受け取る
+    let prod_swap: Arc<Mutex<Option<ringbuf::HeapProd<f32>>>> = Arc::new(Mutex::new(None));
+    let cons_swap: Arc<Mutex<Option<ringbuf::HeapCons<f32>>>> = Arc::new(Mutex::new(None));
+    let prod_swap_input = Arc::clone(&prod_swap);
+    let cons_swap_audio = Arc::clone(&cons_swap);
+
+    let abort_signal_input = Arc::clone(&abort_signal);
+    // コールバック内ではログを書かず、フラグだけ立てて監視ループで報告する
+    let overrun = Arc::new(AtomicBool::new(false));
+    let overrun_input = Arc::clone(&overrun);
+    // 出力デバイスの切り替え中は出力が止まるので、溢れた入力は捨てる
+    let switching = Arc::new(AtomicBool::new(false));
+    let switching_input = Arc::clone(&switching);
+    // 入力が来ていないのか処理が壊れているのかを見分けるため、処理前の入力レベルを測る。
+    // 監視ループが読んでいる間はコールバック側で溜めておき、次の呼び出しで渡す
+    let input_levels = Arc::new(Mutex::new(LevelMeter::default()));
+    let input_levels_input = Arc::clone(&input_levels);
+    let mut pending_levels = LevelMeter::default();
+    let input_fn = move |data: &[T], _: &cpal::InputCallbackInfo| {
+        if let Some(new_prod) = prod_swap_input.try_lock().ok().and_then(|mut p| p.take()) {
+            prod = new_prod;
+        }
+        for frame in data.chunks_exact(channels) {
+            let l = frame[0].to_sample::<f32>();
+            let r = if channels > 1 { frame[1].to_sample::<f32>() } else { l };
+            pending_levels.update([l, r]);
+        }
+        if let Ok(mut levels) = input_levels_input.try_lock() {
+            levels.merge(&pending_levels);
+            pending_levels = LevelMeter::default();
+        }
+        for &sample in data {
+            if prod.try_push(sample.to_sample::<f32>()).is_err() {
+                if switching_input.load(Ordering::Relaxed) { break; }
+                overrun_input.store(true, Ordering::Relaxed);
+                abort_signal_input.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+    };
+
+    let (mut engine, mut amp_factors, geometry_info) = build_engine(sample_rate, &opt.engine)?;
+    // 打ち消しの検証用。直接音だけを出すエンジンを並行して動かし、耳に届く信号との差を求める
+    let mut null_test = if opt.null_test {
+        let params = EngineParams { solo: SoloMode::DirectOnly, ..opt.engine.clone() };
+        let (reference, _, _) = build_engine(sample_rate, &params)?;
+        let probe = NullProbe::new(
+            sample_rate,
+            amp_factors,
+            geometry_info.ct_delays,
+            [geometry_info.shadow_cutoff_l, geometry_info.shadow_cutoff_r],
+            opt.engine.channel_shadow_stages(),
+        );
+        Some((reference, probe, vec![[0.0f32; 2]; opt.block_size]))
+    } else {
+        None
+    };
+    let null_residual = Arc::new(Mutex::new(LevelMeter::default()));
+    let null_residual_audio = Arc::clone(&null_residual);
+    let mut pending_residual = LevelMeter::default();
+    let _ = opt.window.emit("geometry_info", geometry_info);
+    let mut engine_params = opt.engine.clone();
+    let attenuation = opt.engine.attenuation as f64;
+
+    let mut filter_fade_len = (FILTER_FADE_MS * sample_rate / 1000.0) as usize;
+    let mut auto_gain = opt.auto_gain.then(|| AutoGain::new(sample_rate, AUTO_GAIN_THRESHOLD));
+    let gain_reduction = Arc::new(AtomicU32::new(0.0f32.to_bits()));
+    let gain_reduction_audio = Arc::clone(&gain_reduction);
+    let clip_counts = Arc::new([AtomicU32::new(0), AtomicU32::new(0)]);
+    let clip_counts_audio = Arc::clone(&clip_counts);
+    let mut crossover = opt.crossover_hz.map(|hz| [LinkwitzRiley::new(sample_rate, hz), LinkwitzRiley::new(sample_rate, hz)]);
+    // ドライ成分は低域だけを通して、キャンセルした高域を濁らせないようにする
+    let mut dry_lowpass = new_dry_lowpass(opt.dry_lowpass_cutoff, sample_rate);
+    let mut master_gain = LinearSmoother::new(opt.master_gain as f64, (GAIN_RAMP_MS * sample_rate / 1000.0) as usize);
+    // 開始直後は再帰フィルタの状態が落ち着くまで出力を絞る
+    let mut fade_in = fade_in_ramp(opt.fade_in_ms, sample_rate);
+    let mut resampler = (input_rate != opt.output_sample_rate).then(|| Resampler::new(input_rate, opt.output_sample_rate));
+    let underruns = Arc::new(AtomicU32::new(0));
+    let underruns_audio = Arc::clone(&underruns);
+    let block_size = opt.block_size;
+    let mut dry = vec![[0.0f32; 2]; block_size];
+    let mut wet = vec![[0.0f32; 2]; block_size];
+    let mut low = vec![[0.0f32; 2]; block_size];
+    let mut raw = vec![[0.0f32; 2]; block_size];
+    let mut reflections = opt.room_reflections.as_ref()
+        .map(|room| EarlyReflections::new(sample_rate, room, transaural_core::calc_speed_of_sound(opt.engine.temperature)));
+    // 0で処理済み、1でバイパス
+    let mut bypass_mix = new_bypass_fade(0.0, sample_rate);
+    let rate_switch: Arc<Mutex<Option<RateSwitch>>> = Arc::new(Mutex::new(None));
+    let rate_switch_audio = Arc::clone(&rate_switch);
+    // 入力をブロック単位でまとめて処理する。パラメータの確認や集計もブロックごとに行う (outはblock_size以下)
+    let render_block = move |out: &mut [[f32; 2]]| {
+        let n = out.len();
+        if let Some(new_cons) = cons_swap_audio.try_lock().ok().and_then(|mut c| c.take()) {
+            cons = new_cons;
+        }
+        // レートが変わったら、作り直さずに済むエンジンは合わせ込み、それ以外の状態は新しいレートで作り直す
+        if let Some(RateSwitch { sample_rate: new_rate, hrtf: [hrtf, reference_hrtf] }) = rate_switch_audio.try_lock().ok().and_then(|mut s| s.take()) {
+            sample_rate = new_rate as f32;
+            engine.set_sample_rate(sample_rate);
+            if hrtf.is_some() {
+                engine.set_hrtf(hrtf);
+            }
+            if let Some((reference, probe, _)) = null_test.as_mut() {
+                reference.set_sample_rate(sample_rate);
+                if reference_hrtf.is_some() {
+                    reference.set_hrtf(reference_hrtf);
+                }
+                let (_, [_, ct_delays], _) = calc_paths(sample_rate, &engine_params);
+                let shadow_cutoffs = engine_params.shadow_cutoffs(engine_params.lowpass_cutoff_min);
+                *probe = NullProbe::new(sample_rate, amp_factors, ct_delays, shadow_cutoffs, engine_params.channel_shadow_stages());
+            }
+            resampler = (input_rate != new_rate).then(|| Resampler::new(input_rate, new_rate));
+            filter_fade_len = (FILTER_FADE_MS * sample_rate / 1000.0) as usize;
+            auto_gain = opt.auto_gain.then(|| AutoGain::new(sample_rate, AUTO_GAIN_THRESHOLD));
+            crossover = opt.crossover_hz.map(|hz| [LinkwitzRiley::new(sample_rate, hz), LinkwitzRiley::new(sample_rate, hz)]);
+            dry_lowpass = new_dry_lowpass(opt.dry_lowpass_cutoff, sample_rate);
+            master_gain = LinearSmoother::new(master_gain.target(), (GAIN_RAMP_MS * sample_rate / 1000.0) as usize);
+            fade_in = LinearSmoother::new(fade_in.target(), (opt.fade_in_ms * sample_rate / 1000.0) as usize);
+            bypass_mix = new_bypass_fade(bypass_mix.target(), sample_rate);
+            reflections = opt.room_reflections.as_ref()
+                .map(|room| EarlyReflections::new(sample_rate, room, transaural_core::calc_speed_of_sound(engine_params.temperature)));
+        }
+        if primer.as_mut().is_some_and(|p| !p.update(cons.occupied_len())) {
+            out.fill([0.0, 0.0]);
+            return;
+        }
+        let mut pull = || pop_frame(&mut cons, channels);
+        let frozen = opt.freeze_signal.load(Ordering::Relaxed);
+        let eq = opt.eq_update.try_lock().ok().and_then(|mut u| u.take());
+        let filter = filter_update.try_lock().ok().and_then(|mut u| u.take());
+        // 遮蔽のカットオフは位置で決まるので、位置を先に反映してから求める
+        let paths = filter.as_ref().map(|p| {
+            if let Some(position) = p.position.as_ref() {
+                engine_params.position = *position;
+            }
+            engine_params.lowpass_cutoff_min = p.lowpass_cutoff_min;
+            let (factors, [main_delays, ct_delays], _) = calc_paths(sample_rate, &engine_params);
+            (factors, main_delays, ct_delays, engine_params.shadow_cutoffs(p.lowpass_cutoff_min))
+        });
+        // ヌルテストの参照エンジンと見積もりも本体と同じ設定に保たないと、残差が設定のずれを測ってしまう
+        let (reference, probe) = match null_test.as_mut() {
+            Some((reference, probe, _)) => (Some(reference), Some(probe)),
+            None => (None, None),
+        };
+        for e in std::iter::once(&mut engine).chain(reference) {
+            e.freeze(frozen);
+            if let Some(eq) = eq.as_ref() {
+                eq.apply(e);
+            }
+            if let (Some(p), Some((_, main_delays, ct_delays, shadow_cutoffs))) = (filter.as_ref(), paths) {
+                e.set_delay_targets(ct_delays, main_delays);
+                e.set_biquad_targets(shadow_cutoffs, p.highpass_cutoff, p.lowshelf_cutoff, p.lowshelf_gain, filter_fade_len);
+            }
+        }
+        // 凍結中はエンジン側と同じく振幅係数と見積もりも据え置く
+        if let Some((factors, _, ct_delays, shadow_cutoffs)) = paths.filter(|_| !frozen) {
+            amp_factors = factors;
+            if let Some(probe) = probe {
+                probe.set_paths(factors, ct_delays, shadow_cutoffs);
+            }
+        }
+        master_gain.set_target(f32::from_bits(gain_signal.load(Ordering::Relaxed)) as f64);
+        bypass_mix.set_target(if bypass_signal.load(Ordering::Relaxed) { 1.0 } else { 0.0 });
+        let auto_gain_factor = auto_gain.as_ref().map(|ag| ag.gain() as f32);
+        let frames = dry[..n].iter_mut().zip(&mut wet[..n]).zip(&mut low[..n]).zip(&mut raw[..n]);
+        for (((dry, wet), low), raw) in frames {
+            let frame = match resampler.as_mut() {
+                Some(rs) => rs.next(&mut pull),
+                None => pull(),
+            };
+            // 入力が足りなければ無音を出す
+            let Some([mut l, mut r]) = frame else {
+                underruns_audio.fetch_add(1, Ordering::Relaxed);
+                *dry = [0.0, 0.0];
+                *wet = [0.0, 0.0];
+                *low = [0.0, 0.0];
+                *raw = [0.0, 0.0];
+                continue;
+            };
+            [l, r] = apply_master_gain(opt.gain_stage, GainStage::PreProcess, [l, r], &mut master_gain);
+            *raw = [l, r];
+            if opt.width != 1.0 {
+                [l, r] = apply_width([l, r], opt.width);
+            }
+            if let Some(g) = auto_gain_factor {
+                l *= g;
+                r *= g;
+            }
+            *dry = filter_dry(&mut dry_lowpass, [l, r]);
+            *wet = match crossover.as_mut() {
+                // 高域だけキャンセル処理して低域と足し戻す
+                Some([xo_l, xo_r]) => {
+                    let (low_l, high_l) = xo_l.split(l as f64);
+                    let (low_r, high_r) = xo_r.split(r as f64);
+                    *low = [low_l as f32, low_r as f32];
+                    [high_l as f32, high_r as f32]
+                }
+                None => [l, r],
+            };
+        }
+        engine.process_block(&wet[..n], out, attenuation, &amp_factors);
+        if let Some((reference, probe, ref_out)) = null_test.as_mut() {
+            reference.process_block(&wet[..n], &mut ref_out[..n], attenuation, &amp_factors);
+            for (&out, &ref_out) in out.iter().zip(&ref_out[..n]) {
+                pending_residual.update(probe.process(out, ref_out));
+            }
+            if let Ok(mut residual) = null_residual_audio.try_lock() {
+                residual.merge(&pending_residual);
+                pending_residual = LevelMeter::default();
+            }
+        }
+        let clips = engine.take_clip_counts();
+        if clips != [0, 0] {
+            clip_counts_audio[0].fetch_add(clips[0], Ordering::Relaxed);
+            clip_counts_audio[1].fetch_add(clips[1], Ordering::Relaxed);
+        }
+        let w = opt.wet_dry;
+        let d = 1.0 - opt.wet_dry;
+        for (((out, &[l, r]), low), &[raw_l, raw_r]) in out.iter_mut().zip(&dry[..n]).zip(&low[..n]).zip(&raw[..n]) {
+            let [mut out_l, mut out_r] = *out;
+            if crossover.is_some() {
+                out_l += low[0];
+                out_r += low[1];
+            }
+            if let Some(er) = reflections.as_mut() {
+                [out_l, out_r] = er.process([out_l, out_r]);
+            }
+            if let Some(ag) = auto_gain.as_mut() {
+                ag.update([out_l, out_r]);
+            }
+            let fade = fade_in.next_value() as f32;
+            let bypass = bypass_mix.next_value() as f32;
+            let mixed = [out_l * w + l * d, out_r * w + r * d];
+            let faded = crossfade_bypass(mixed, [raw_l, raw_r], bypass).map(|x| x * fade);
+            *out = apply_master_gain(opt.gain_stage, GainStage::PostProcess, faded, &mut master_gain);
+        }
+        if let Some(ag) = auto_gain.as_ref() {
+            let reduction_db = -20.0 * ag.gain().log10() as f32;
+            gain_reduction_audio.store(reduction_db.to_bits(), Ordering::Relaxed);
+        }
+    };
+
+    // スペクトル表示用のタップ。コールバック内ではコピーだけ行い、FFTは別スレッドで計算する
+    let (spectrum_prod, spectrum_cons) = match opt.spectrum_size {
+        Some(size) => {
+            let (prod, cons) = ringbuf::HeapRb::<f32>::new(size * 4).split();
+            (Some(prod), Some((size, cons)))
+        }
+        None => (None, None),
+    };
+    // 相関計用のタップ (L/Rを交互に入れる)。計算は監視ループで行う
+    let (correlation_prod, mut correlation_cons) = ringbuf::HeapRb::<f32>::new(sample_rate as usize).split();
+
+    let output_channel_count = opt.output_channel_count as usize;
+    // 左右が入れ替わって配線されている場合は、出力先のチャンネルを入れ替えて補正する
+    let [map_l, map_r] = output_channel_map(opt.output_channels, opt.swap_output_channels);
+    let output_format = opt.output_format;
+    // 出力デバイスを作り直しても処理の状態を引き継げるよう、出力側の状態は共有しておく
+    let device_fade = new_device_fade(1.0, sample_rate);
+    let output_state = Arc::new(Mutex::new((render_block, spectrum_prod, correlation_prod, device_fade)));
+    let fade_target = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+    // 浮動小数点の出力にはディザをかけない
+    let dither_bits = (opt.dither && !T::FORMAT.is_float()).then(|| T::FORMAT.bits_per_sample());
+    let make_output_fn = || {
+        let output_state = Arc::clone(&output_state);
+        let fade_target = Arc::clone(&fade_target);
+        let pause_signal = Arc::clone(&pause_signal);
+        let mut dither = dither_bits.map(|bits| [Dither::new(bits, 0x1234_5678), Dither::new(bits, 0x9abc_def0)]);
+        let mut block = vec![[0.0f32; 2]; block_size];
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            // 切り替えで新旧のストリームが重なる間は、片方だけが処理を進める
+            let Ok(mut state) = output_state.try_lock() else {
+                data.fill(T::EQUILIBRIUM);
+                return;
+            };
+            let (render_block, spectrum_prod, correlation_prod, device_fade) = &mut *state;
+            device_fade.set_target(f32::from_bits(fade_target.load(Ordering::Relaxed)) as f64);
+            let paused = pause_signal.load(Ordering::Relaxed);
+            for chunk in data.chunks_mut(block.len() * output_channel_count) {
+                let frames = chunk.len() / output_channel_count;
+                render_block(&mut block[..frames]);
+                for (sample, &sig) in chunk.chunks_exact_mut(output_channel_count).zip(&block[..frames]) {
+                    if let Some(prod) = spectrum_prod.as_mut() {
+                        let _ = prod.try_push((sig[0] + sig[1]) * 0.5);
+                    }
+                    if correlation_prod.vacant_len() >= 2 {
+                        let _ = correlation_prod.try_push(sig[0]);
+                        let _ = correlation_prod.try_push(sig[1]);
+                    }
+                    let fade = device_fade.next_value() as f32;
+                    let mut out = output_mix(sig, output_channel_count, output_format).map(|x| x * fade);
+                    if let Some([d_l, d_r]) = dither.as_mut() {
+                        out[0] += d_l.next_value();
+                        out[1] += d_r.next_value();
+                    }
+                    if paused {
+                        // 一時停止中も入力は消費し続けて、再開時に古いサンプルが出ないようにする
+                        sample.fill(T::EQUILIBRIUM);
+                        continue;
+                    }
+                    write_frame(sample, out, [map_l, map_r]);
+                }
+            }
+        }
+    };
+
+    let make_err_fn = |direction: &'static str| {
+        let abort_signal = Arc::clone(&abort_signal);
+        let window = opt.window.clone();
+        move |e: cpal::StreamError| {
+            log::error!("Stream error occured: {:?}", e);
+            if abort_on_device_loss(&e, &abort_signal) {
+                let _ = window.emit("device_lost", DeviceLostPayload { direction: direction.to_owned() });
+            }
+        }
+    };
+
+    let mut output_config = cpal::StreamConfig {
+        channels: opt.output_channel_count,
+        sample_rate: opt.output_sample_rate,
+        ..opt.config.clone()
+    };
+
+    let input_stream = opt.input.build_input_stream(opt.config, input_fn, make_err_fn("input"), None)
+        .map_err(|e| AudioError::StreamFailed(format!("failed to build input stream: {}", e)))?;
+    let mut output_stream = opt.output.build_output_stream(&output_config, make_output_fn(), make_err_fn("output"), None)
+        .map_err(|e| AudioError::StreamFailed(format!("failed to build output stream: {}", e)))?;
+
+    log::info!("Started streams with {} ms of latency.", &opt.latency);
+    input_stream.play().map_err(|e| AudioError::StreamFailed(format!("failed to play input stream: {}", e)))?;
+    output_stream.play().map_err(|e| AudioError::StreamFailed(format!("failed to play output stream: {}", e)))?;
+    // 実際に鳴っている出力側の設定を通知する (入力とレートやチャンネル数が異なることがある)
+    let _ = opt.window.emit("stream_config", stream_config_payload(&output_config, T::FORMAT));
+
+    let spectrum_worker = spectrum_cons.map(|(size, mut cons)| {
+        let abort_signal = Arc::clone(&abort_signal);
+        let window = opt.window.clone();
+        std::thread::spawn(move || {
+            let mut analyzer = SpectrumAnalyzer::new(size);
+            let mut block = vec![0.0f32; size];
+            while !abort_signal.load(Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(SPECTRUM_INTERVAL_MS));
+                if cons.occupied_len() < size { continue; }
+                // 最新のブロックだけを解析する
+                cons.skip(cons.occupied_len() - size);
+                cons.pop_slice(&mut block);
+                let _ = window.emit("spectrum", SpectrumPayload { bins: analyzer.analyze(&block) });
+            }
+        })
+    });
+
+    let mut dur = std::time::Duration::from_millis(opt.latency as u64);
+    let started_at = std::time::Instant::now();
+    let mut latency_adapter = opt.adaptive_latency
+        .then(|| LatencyAdapter::new(opt.latency, ADAPTIVE_LATENCY_STEP_MS, MAX_ADAPTIVE_LATENCY_MS));
+    let mut last_reduction_db = 0.0f32;
+    let mut correlation_meter = CorrelationMeter::new(sample_rate);
+    let mut last_correlation = 0.0f32;
+    let mut last_mono_compat = 0.0f32;
+    let mut mono_cancel_warned = false;
+    let clip_window = std::time::Duration::from_millis(CLIP_WINDOW_MS);
+    let mut clip_window_start = std::time::Instant::now();
+    while !abort_signal.load(Ordering::Relaxed) {
+        std::thread::sleep(dur);
+        if let Some(device_id) = output_switch.lock().unwrap().take() {
+            switching.store(true, Ordering::Relaxed);
+            fade_target.store(0.0f32.to_bits(), Ordering::Relaxed);
+            // フェードアウトが出力バッファを抜けきるまで待つ
+            std::thread::sleep(std::time::Duration::from_millis(DEVICE_FADE_MS as u64) + dur);
+            let new_stream = cpal::host_from_id(device_id.0).ok()
+                .and_then(|host| host.device_by_id(&device_id))
+                .ok_or_else(|| AudioError::DeviceNotFound(format!("output device not found: {}", device_id.1)))
+                .and_then(|device| {
+                    let config = negotiate_output_config(&device, &output_config);
+                    if config.sample_rate != input_rate && !cfg!(feature = "resampling") {
+                        return Err(AudioError::UnsupportedConfig(format!("{} Hz output needs resampling, which this build does not include", config.sample_rate)));
+                    }
+                    // HRIRの読み直しに失敗したら、ストリームを開く前に切り替えをやめる
+                    let switch = (config.sample_rate != output_config.sample_rate)
+                        .then(|| prepare_rate_switch(config.sample_rate, &opt.engine, opt.null_test))
+                        .transpose()?;
+                    let stream = device.build_output_stream(&config, make_output_fn(), make_err_fn("output"), None)
+                        .map_err(|e| AudioError::StreamFailed(format!("failed to build output stream: {}", e)))?;
+                    Ok((stream, config, switch))
+                });
+            match new_stream {
+                Ok((stream, config, switch)) => {
+                    // 古いストリームはここで閉じる
+                    output_stream = stream;
+                    // 新しいストリームが鳴り始める前に、処理側を新しいレートに切り替えておく
+                    if let Some(switch) = switch {
+                        log::info!("Output sample rate changed from {} to {} Hz.", output_config.sample_rate, config.sample_rate);
+                        *rate_switch.lock().unwrap() = Some(switch);
+                        output_state.lock().unwrap().3 = new_device_fade(0.0, config.sample_rate as f32);
+                        correlation_meter = CorrelationMeter::new(config.sample_rate as f32);
+                    }
+                    output_config = config;
+                    let _ = opt.window.emit("stream_config", stream_config_payload(&output_config, T::FORMAT));
+                    if let Err(e) = output_stream.play() {
+                        let _ = opt.window.emit("error", AudioError::StreamFailed(format!("failed to play output stream: {}", e)));
+                        abort_signal.store(true, Ordering::Relaxed);
+                    }
+                }
+                // 切り替えに失敗したら元のデバイスで再開する
+                Err(e) => {
+                    log::error!("Failed to switch output device: {:?}", e);
+                    let _ = opt.window.emit("error", e);
+                }
+            }
+            fade_target.store(1.0f32.to_bits(), Ordering::Relaxed);
+            // 新しい出力が読み始めるまでは入力の溢れを許す
+            std::thread::sleep(dur);
+            switching.store(false, Ordering::Relaxed);
+        }
+        if clip_window_start.elapsed() >= clip_window {
+            let _ = opt.window.emit("clip_count", ClipCountPayload {
+                left: clip_counts[0].swap(0, Ordering::Relaxed),
+                right: clip_counts[1].swap(0, Ordering::Relaxed),
+                window_ms: clip_window_start.elapsed().as_millis() as u64,
+            });
+            let frames = underruns.swap(0, Ordering::Relaxed);
+            if frames > 0 {
+                let _ = opt.window.emit("underrun", UnderrunPayload {
+                    frames,
+                    window_ms: clip_window_start.elapsed().as_millis() as u64,
+                });
+            }
+            let adapting = started_at.elapsed() < std::time::Duration::from_millis(ADAPTIVE_LATENCY_WINDOW_MS);
+            if let Some(latency_ms) = latency_adapter.as_mut().filter(|_| adapting).and_then(|a| a.observe(frames > 0)) {
+                // ストリームはそのままで、プリロールを増やしたリングバッファに差し替える
+                let samples = latency_ms * input_rate as usize / 1000 * channels;
+                let (new_prod, new_cons) = ringbuf::HeapRb::<f32>::from(vec![0.0f32; samples]).split();
+                switching.store(true, Ordering::Relaxed);
+                *cons_swap.lock().unwrap() = Some(new_cons);
+                *prod_swap.lock().unwrap() = Some(new_prod);
+                dur = std::time::Duration::from_millis(latency_ms as u64);
+                log::info!("Increased latency to {} ms after repeated underruns.", latency_ms);
+                let _ = opt.window.emit("latency_adapted", LatencyPayload { latency_ms });
+                // 新しいバッファが読み始められるまでは入力の溢れを許す
+                std::thread::sleep(dur);
+                switching.store(false, Ordering::Relaxed);
+            }
+            clip_window_start = std::time::Instant::now();
+        }
+        let reduction_db = f32::from_bits(gain_reduction.load(Ordering::Relaxed));
+        if (reduction_db - last_reduction_db).abs() >= 0.1 {
+            let _ = opt.window.emit("auto_gain", AutoGainPayload { reduction_db });
+            last_reduction_db = reduction_db;
+        }
+        let levels = {
+            let mut meter = input_levels.lock().unwrap();
+            (!meter.is_empty()).then(|| meter.take())
+        };
+        if let Some((peak, rms)) = levels {
+            let _ = opt.window.emit("input_levels", LevelsPayload { peak, rms });
+        }
+        let residual = {
+            let mut meter = null_residual.lock().unwrap();
+            (!meter.is_empty()).then(|| meter.take())
+        };
+        if let Some((peak, rms)) = residual {
+            let _ = opt.window.emit("null_residual", LevelsPayload { peak, rms });
+        }
+        // 書き込み途中のペアを読まないよう、2サンプル揃っている分だけ取り出す
+        while correlation_cons.occupied_len() >= 2 {
+            let (Some(l), Some(r)) = (correlation_cons.try_pop(), correlation_cons.try_pop()) else { break };
+            correlation_meter.update([l, r]);
+        }
+        let correlation = correlation_meter.value();
+        if (correlation - last_correlation).abs() >= 0.01 {
+            let _ = opt.window.emit("correlation", CorrelationPayload { correlation });
+            last_correlation = correlation;
+        }
+        if opt.mono_compat {
+            let mono_compat_db = correlation_meter.mono_compat_db();
+            let severe = mono_compat_db < MONO_CANCEL_WARN_DB;
+            if severe && !mono_cancel_warned {
+                log::warn!("Output loses {:.1} dB when summed to mono (wet/dry {}).", -mono_compat_db, opt.wet_dry);
+            }
+            mono_cancel_warned = severe;
+            if (mono_compat_db - last_mono_compat).abs() >= 0.1 {
+                let _ = opt.window.emit("mono_compat", MonoCompatPayload { mono_compat_db, severe });
+                last_mono_compat = mono_compat_db;
+            }
+        }
+    }
+
+    drop(input_stream);
+    drop(output_stream);
+    if let Some(worker) = spectrum_worker {
+        let _ = worker.join();
+    }
+    
+    if overrun.load(Ordering::Relaxed) {
+        log::warn!("Output stream fell behind; increase latency");
+    }
+    log::info!("Closed safely!");
+    Ok(())
+}
+
+fn new_dry_lowpass(cutoff: Option<f32>, sample_rate: f32) -> Option<[BiquadFilter<f64>; 2]> {
+    cutoff.map(|hz| [BiquadFilter::low_pass(sample_rate, hz), BiquadFilter::low_pass(sample_rate, hz)])
+}
+
+// ドライ成分だけに掛けるローパス。ウェット側には入力をそのまま渡す
+fn filter_dry(dry_lowpass: &mut Option<[BiquadFilter<f64>; 2]>, [l, r]: [f32; 2]) -> [f32; 2] {
+    match dry_lowpass.as_mut() {
+        Some([lp_l, lp_r]) => [lp_l.process(l as f64) as f32, lp_r.process(r as f64) as f32],
+        None => [l, r],
+    }
+}
+
+// 0から1までfade_in_msで立ち上がる包絡線
+fn fade_in_ramp(fade_in_ms: f32, sample_rate: f32) -> LinearSmoother {
+    let mut ramp = LinearSmoother::new(0.0, (fade_in_ms * sample_rate / 1000.0) as usize);
+    ramp.set_target(1.0);
+    ramp
+}
+
+// バイパスの切り替えはBYPASS_FADE_MSかけて処理後の信号と入力を入れ替える
+fn new_bypass_fade(initial: f64, sample_rate: f32) -> LinearSmoother {
+    LinearSmoother::new(initial, (BYPASS_FADE_MS * sample_rate / 1000.0) as usize)
+}
+
+// bypassが0で処理後の信号、1で入力そのもの
+fn crossfade_bypass(processed: [f32; 2], raw: [f32; 2], bypass: f32) -> [f32; 2] {
+    [processed[0] + (raw[0] - processed[0]) * bypass, processed[1] + (raw[1] - processed[1]) * bypass]
+}
+
+// 設定した段 (stage) がatのときだけマスターゲインを1フレーム進めて掛ける
+fn apply_master_gain(stage: GainStage, at: GainStage, frame: [f32; 2], master_gain: &mut LinearSmoother) -> [f32; 2] {
+    if stage != at {
+        return frame;
+    }
+    let gain = master_gain.next_value() as f32;
+    frame.map(|x| x * gain)
+}
+
+// デバイス切り替え時のフェード (DEVICE_FADE_MSで目標まで直線的に動く)
+fn new_device_fade(initial: f64, sample_rate: f32) -> LinearSmoother {
+    LinearSmoother::new(initial, (DEVICE_FADE_MS * sample_rate / 1000.0) as usize)
+}
+
+fn output_channel_map([left, right]: [usize; 2], swap: bool) -> [usize; 2] {
+    if swap { [right, left] } else { [left, right] }
+}
+
+// 出力デバイスのチャンネル数と形式に合わせる。モノラルのデバイスにはL/Rの平均を出す
+fn output_mix(sig: [f32; 2], channel_count: usize, format: OutputFormat) -> [f32; 2] {
+    if channel_count == 1 {
+        let mix = (sig[0] + sig[1]) * 0.5;
+        return [mix, mix];
+    }
+    match format {
+        OutputFormat::Stereo => sig,
+        OutputFormat::MidSide => encode_mid_side(sig),
+    }
+}
+
+// L/Rをフレーム内のマッピング先に書き込み、それ以外のチャンネルは無音にする (モノラルなら先頭だけ)
+fn write_frame<T: cpal::Sample + FromSample<f32>>(frame: &mut [T], out: [f32; 2], [map_l, map_r]: [usize; 2]) {
+    frame.fill(T::EQUILIBRIUM);
+    if frame.len() == 1 {
+        frame[0] = out[0].to_sample();
+    } else {
+        frame[map_l] = out[0].to_sample();
+        frame[map_r] = out[1].to_sample();
+    }
+}
+
+// M/Sに分解してサイド成分をwidth倍する (0でモノラル、1で元のまま)
+fn apply_width([l, r]: [f32; 2], width: f32) -> [f32; 2] {
+    let mid = (l + r) * 0.5;
+    let side = (l - r) * 0.5 * width;
+    [mid + side, mid - side]
+}
+
+fn encode_mid_side([l, r]: [f32; 2]) -> [f32; 2] {
+    [(l + r) * std::f32::consts::FRAC_1_SQRT_2, (l - r) * std::f32::consts::FRAC_1_SQRT_2]
+}
+
+fn ensure_finite(name: &str, value: f32) -> Result<f32, AudioError> {
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(AudioError::InvalidParameter(format!("{} must be a finite number", name)))
+    }
+}
+
+// 負のゲインや範囲外のミックス比は逆相や発散の原因になるので、有効な範囲に丸める
+fn clamp_mix_params(master_gain: f32, attenuation: f32, wet_dry: f32) -> (f32, f32, f32) {
+    (master_gain.max(0.0), attenuation.clamp(-1.0, 1.0), wet_dry.clamp(0.0, 1.0))
+}
+
+fn ensure_allpass_order(order: usize) -> Result<(), AudioError> {
+    match allpass_poles(order) {
+        Some(_) => Ok(()),
+        None => Err(AudioError::InvalidParameter(format!("unsupported all-pass order: {}", order))),
+    }
+}
+
+fn ensure_allpass_poles(poles_a: &[f64], poles_b: &[f64]) -> Result<(), AudioError> {
+    if poles_a.is_empty() || poles_a.len() != poles_b.len() {
+        return Err(AudioError::InvalidParameter("allpassPoles must be two non-empty lists of the same length".to_owned()));
+    }
+    if poles_a.iter().chain(poles_b).all(|p| p.is_finite() && *p > 0.0) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter("allpassPoles must be positive".to_owned()))
+    }
+}
+
+fn ensure_shadow_stages(stages: usize) -> Result<(), AudioError> {
+    if (1..=MAX_SHADOW_STAGES).contains(&stages) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("unsupported number of shadow filter stages: {}", stages)))
+    }
+}
+
+fn ensure_delay_override(delays: [f64; 2]) -> Result<(), AudioError> {
+    // 遅延線は512サンプルなので、補間で読む前後のサンプルも収まる範囲に制限する
+    if delays.iter().all(|d| d.is_finite() && (1.0..=500.0).contains(d)) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter("delayOverride must be between 1 and 500 samples".to_owned()))
+    }
+}
+
+fn ensure_all_pass(params: &AllPassParams) -> Result<(), AudioError> {
+    let cutoff = ensure_finite("phaseAlign.cutoff", params.cutoff)?;
+    let q = ensure_finite("phaseAlign.q", params.q)?;
+    if cutoff > 0.0 && q > 0.0 {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter("phaseAlign cutoff and q must be positive".to_owned()))
+    }
+}
+
+fn ensure_speaker_eq(eq: &SpeakerEq) -> Result<(), AudioError> {
+    if eq.left.len() > MAX_EQ_BANDS || eq.right.len() > MAX_EQ_BANDS {
+        return Err(AudioError::InvalidParameter(format!("speakerEq allows at most {} bands per channel", MAX_EQ_BANDS)));
+    }
+    for band in eq.left.iter().chain(eq.right.iter()) {
+        let frequency = ensure_finite("speakerEq.frequency", band.frequency)?;
+        ensure_finite("speakerEq.gain", band.gain)?;
+        let q = ensure_finite("speakerEq.q", band.q)?;
+        if frequency <= 0.0 || q <= 0.0 {
+            return Err(AudioError::InvalidParameter("speakerEq frequency and q must be positive".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+fn ensure_cancel_order(order: usize) -> Result<(), AudioError> {
+    if (1..=8).contains(&order) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("unsupported cancellation order: {}", order)))
+    }
+}
+
+fn ensure_ceiling(ceiling: f32) -> Result<f32, AudioError> {
+    if ceiling.is_finite() && ceiling > 0.0 {
+        Ok(ceiling)
+    } else {
+        Err(AudioError::InvalidParameter(format!("ceiling must be positive: {}", ceiling)))
+    }
+}
+
+fn ensure_output_channels(map: [usize; 2], channel_count: u16) -> Result<(), AudioError> {
+    if map[0] == map[1] {
+        return Err(AudioError::InvalidParameter("outputChannels must be distinct".to_owned()));
+    }
+    if map.iter().any(|&ch| ch >= channel_count as usize) {
+        return Err(AudioError::InvalidParameter(format!("output device has only {} channels", channel_count)));
+    }
+    Ok(())
+}
+
+fn ensure_amp_factor_range(min: f32, max: f32) -> Result<(), AudioError> {
+    ensure_finite("ampFactorMin", min)?;
+    ensure_finite("ampFactorMax", max)?;
+    if 0.0 <= min && min <= max {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter("ampFactorMin must be between 0 and ampFactorMax".to_owned()))
+    }
+}
+
+fn ensure_room(room: &RoomParams) -> Result<(), AudioError> {
+    for (name, value) in [("width", room.width), ("depth", room.depth), ("height", room.height)] {
+        if !(value > 0.0 && value <= MAX_ROOM_DIMENSION) {
+            return Err(AudioError::InvalidParameter(format!("room {} must be between 0 and {} m", name, MAX_ROOM_DIMENSION)));
+        }
+    }
+    if !(0.0..=1.0).contains(&room.reflectivity) {
+        return Err(AudioError::InvalidParameter("room reflectivity must be between 0 and 1".to_owned()));
+    }
+    Ok(())
+}
+
+fn ensure_emphasis(db: f32) -> Result<(), AudioError> {
+    if (0.0..=MAX_EMPHASIS_DB).contains(&db) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("emphasis must be between 0 and {} dB", MAX_EMPHASIS_DB)))
+    }
+}
+
+fn ensure_tilt(db_per_octave: f32) -> Result<(), AudioError> {
+    if (-MAX_TILT_DB_PER_OCTAVE..=MAX_TILT_DB_PER_OCTAVE).contains(&db_per_octave) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("tiltDbPerOctave must be between -{0} and {0}", MAX_TILT_DB_PER_OCTAVE)))
+    }
+}
+
+fn ensure_prime_fill(fill: f32) -> Result<(), AudioError> {
+    // 1まで溜めると最初のコールバックでリングバッファが溢れる
+    if (0.0..1.0).contains(&fill) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter("primeFill must be at least 0 and below 1".to_owned()))
+    }
+}
+
+// 処理する長さがsample_rateに比例するので、現実的な範囲に制限して確保量を抑える
+fn ensure_sample_rate(sample_rate: f32) -> Result<(), AudioError> {
+    if (MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("sampleRate must be between {} and {}: {}", MIN_SAMPLE_RATE, MAX_SAMPLE_RATE, sample_rate)))
+    }
+}
+
+fn ensure_block_size(size: usize) -> Result<(), AudioError> {
+    if (1..=MAX_BLOCK_SIZE).contains(&size) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("blockSize must be between 1 and {}", MAX_BLOCK_SIZE)))
+    }
+}
+
+fn ensure_spectrum_size(size: usize) -> Result<(), AudioError> {
+    if FFT_SIZES.contains(&size) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("unsupported FFT size: {}", size)))
+    }
+}
+
+const MIN_TEMPERATURE_C: f32 = -50.0;
+const MAX_TEMPERATURE_C: f32 = 60.0;
+
+// 絶対零度以下では音速の計算がNaNになるので、室内で現実的な範囲に制限する
+fn ensure_temperature(t_c: f32) -> Result<(), AudioError> {
+    if (MIN_TEMPERATURE_C..=MAX_TEMPERATURE_C).contains(&t_c) {
+        Ok(())
+    } else {
+        Err(AudioError::InvalidParameter(format!("temperature must be between {} and {} °C: {}", MIN_TEMPERATURE_C, MAX_TEMPERATURE_C, t_c)))
+    }
+}
+
+// デバイスが抜かれたらルーティングを止める。止めたときはtrueを返す
+fn abort_on_device_loss(e: &cpal::StreamError, abort_signal: &AtomicBool) -> bool {
+    let lost = matches!(e, cpal::StreamError::DeviceNotAvailable);
+    if lost {
+        abort_signal.store(true, Ordering::Relaxed);
+    }
+    lost
+}
+
+fn stream_config_payload(config: &cpal::StreamConfig, format: cpal::SampleFormat) -> StreamConfigPayload {
+    StreamConfigPayload {
+        sample_rate: config.sample_rate,
+        channels: config.channels,
+        buffer_size: match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => Some(frames),
+            cpal::BufferSize::Default => None,
+        },
+        sample_format: format.to_string(),
+    }
+}
+
+// 切り替え先が今のレートに対応していなければ、そのデバイスの既定のレートで開く (チャンネル数とバッファサイズはそのまま)
+fn negotiate_output_config(device: &cpal::Device, current: &cpal::StreamConfig) -> cpal::StreamConfig {
+    let supports_current = device.supported_output_configs()
+        .map(|mut configs| configs.any(|c| (c.min_sample_rate()..=c.max_sample_rate()).contains(&current.sample_rate)))
+        .unwrap_or(false);
+    match device.default_output_config() {
+        Ok(default) if !supports_current => cpal::StreamConfig { sample_rate: default.sample_rate(), ..current.clone() },
+        _ => current.clone(),
+    }
+}
+
+fn prepare_rate_switch(sample_rate: u32, params: &EngineParams, null_test: bool) -> Result<RateSwitch, AudioError> {
+    let load = || params.hrir.as_ref().map(|hrir| transaural_core::load_hrtf(sample_rate as f32, hrir)).transpose();
+    let reference_hrtf = if null_test { load()? } else { None };
+    Ok(RateSwitch { sample_rate, hrtf: [load()?, reference_hrtf] })
+}
+
+// 範囲の確認は換算後の摂氏で行う (華氏や絶対温度で絶対零度を下回る値も弾く)
+fn to_celsius(t: f32, unit: &str) -> Result<f32, AudioError> {
+    let t_c = match unit.to_lowercase().as_str() {
+        "c" => t,
+        "f" => (t - 32.0) * 5.0 / 9.0,
+        "k" => t - 273.15,
+        _ => return Err(AudioError::InvalidParameter(format!("unknown temperature unit: {}", unit))),
+    };
+    ensure_temperature(t_c)?;
+    Ok(t_c)
+}
+
+// 入力の1フレーム分を取り出す。1フレームに満たなければ何も読まずにNoneを返す
+fn pop_frame(cons: &mut impl Consumer<Item = f32>, channels: usize) -> Option<[f32; 2]> {
+    if cons.occupied_len() < channels { return None; }
+    let l = cons.try_pop()?;
+    // モノラル入力は両チャンネルに複製
+    let r = if channels > 1 { cons.try_pop()? } else { l };
+    cons.skip(channels.saturating_sub(2));
+    Some([l, r])
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let arc= Arc::new(AtomicBool::new(false));
+    logger::init();
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .manage(AppState {
-            abort_signal: Arc::clone(&arc),
+        .setup(|app| {
+            // 前回の設定を読み込んでフロントエンドに知らせる。リスナーの登録が間に合わなかったときはload_last_settingsで取りに来てもらう
+            let handle = app.handle().clone();
+            match app_config_dir(&handle).and_then(|dir| settings::load_last(&dir).map_err(AudioError::Io)) {
+                Ok(Some(settings)) => {
+                    let _ = handle.emit("last_settings", &settings);
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Failed to load last settings: {:?}", e),
+            }
+            std::thread::spawn(move || watch_devices(handle));
+            Ok(())
         })
+        .manage(AppState::new())
         .invoke_handler(tauri::generate_handler![
+            list_hosts,
+            get_capabilities,
             get_audio_devices,
             set_audio_devices,
             abort_audio_routing,
+            is_routing_active,
+            pause_audio_routing,
+            resume_audio_routing,
+            set_master_gain,
+            set_bypass_ramped,
+            freeze_filters,
+            set_speaker_eq,
+            update_filter_params,
+            switch_output_device,
+            get_impulse_response,
+            get_engine_coefficients,
+            process_file_offline,
+            benchmark_engine,
+            measure_latency,
+            get_recommended_latency,
+            play_test_tone,
+            play_center_check,
+            save_last_settings,
+            load_last_settings,
+            validate_config,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
-}
\ No newline at end of file
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                stop_routing(&app.state::<AppState>());
+                flush_last_settings(app);
+            }
+        });
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_units_agree() {
+        let fahrenheit = to_celsius(72.0, "F").unwrap();
+        let kelvin = to_celsius(295.35, "k").unwrap();
+        assert!((fahrenheit - 22.222).abs() < 1e-3);
+        assert!((kelvin - 22.2).abs() < 1e-3);
+        let speed = transaural_core::calc_speed_of_sound(22.2);
+        assert!((transaural_core::calc_speed_of_sound(fahrenheit) - speed).abs() < 0.05);
+        assert!((transaural_core::calc_speed_of_sound(kelvin) - speed).abs() < 0.05);
+        assert!(to_celsius(20.0, "r").is_err());
+    }
+
+    #[test]
+    fn temperature_outside_range_is_rejected() {
+        // 換算すると絶対零度を下回る値や、室内として非現実的な値
+        for (t, unit) in [(-500.0, "f"), (0.0, "k"), (-273.15, "c"), (61.0, "c"), (-51.0, "c"), (f32::NAN, "c")] {
+            assert!(matches!(to_celsius(t, unit), Err(AudioError::InvalidParameter(_))), "{} {}", t, unit);
+        }
+        assert!(to_celsius(60.0, "c").is_ok());
+        assert!(ensure_temperature(-50.0).is_ok());
+    }
+
+    #[test]
+    fn device_loss_sets_the_abort_flag() {
+        let abort_signal = AtomicBool::new(false);
+        assert!(!abort_on_device_loss(&cpal::StreamError::BufferUnderrun, &abort_signal));
+        assert!(!abort_signal.load(Ordering::Relaxed));
+        assert!(abort_on_device_loss(&cpal::StreamError::DeviceNotAvailable, &abort_signal));
+        assert!(abort_signal.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn pause_toggles_without_aborting() {
+        let state = AppState::new();
+        state.set_paused(true);
+        assert!(state.pause_signal.load(Ordering::Relaxed));
+        state.set_paused(false);
+        assert!(!state.pause_signal.load(Ordering::Relaxed));
+        assert!(!state.abort_signal.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn unknown_host_is_rejected() {
+        assert!(matches!(get_host(Some("not-a-host")), Err(AudioError::HostUnavailable(_))));
+        // list_hostsが返す名前はそのままget_hostに渡せる
+        for name in list_hosts() {
+            assert!(name.parse::<cpal::HostId>().is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn out_of_range_mix_params_are_clamped() {
+        assert_eq!(clamp_mix_params(-0.5, 0.7, 0.5), (0.0, 0.7, 0.5));
+        assert_eq!(clamp_mix_params(1.0, 3.0, 5.0), (1.0, 1.0, 1.0));
+        assert_eq!(clamp_mix_params(1.0, -3.0, -0.5), (1.0, -1.0, 0.0));
+        assert_eq!(clamp_mix_params(2.0, -0.7, 1.0), (2.0, -0.7, 1.0));
+    }
+
+    #[test]
+    fn non_finite_params_are_rejected() {
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert!(matches!(ensure_finite("wetDry", value), Err(AudioError::InvalidParameter(_))), "{}", value);
+        }
+        assert_eq!(ensure_finite("wetDry", 0.5).unwrap(), 0.5);
+    }
+
+    #[test]
+    fn input_filter_excludes_output_only_devices() {
+        use cpal::DeviceDirection::*;
+        let wanted = parse_direction(Some("input")).unwrap();
+        assert!(matches_direction(Input, wanted));
+        assert!(matches_direction(Duplex, wanted));
+        assert!(!matches_direction(Output, wanted));
+        assert!(!matches_direction(Unknown, wanted));
+        for dir in [Input, Output, Duplex, Unknown] {
+            assert!(matches_direction(dir, parse_direction(None).unwrap()));
+        }
+        assert!(matches!(parse_direction(Some("both")), Err(AudioError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn emitted_stream_config_matches_the_request() {
+        let host_id = cpal::default_host().id();
+        let (buffer_size, _) = resolve_buffer_size(host_id, Some(256));
+        let config = cpal::StreamConfig { channels: 4, sample_rate: 48000, buffer_size };
+        let payload = serde_json::to_value(stream_config_payload(&config, cpal::SampleFormat::F32)).unwrap();
+        let expected_buffer = if is_jack_host(host_id) { serde_json::Value::Null } else { serde_json::json!(256) };
+        assert_eq!(payload, serde_json::json!({
+            "sampleRate": 48000,
+            "channels": 4,
+            "bufferSize": expected_buffer,
+            "sampleFormat": "f32",
+        }));
+    }
+
+    #[test]
+    fn mapped_channels_alone_receive_signal() {
+        let mut frame = [1.0f32; 4];
+        write_frame(&mut frame, [0.25, -0.5], [2, 3]);
+        assert_eq!(frame, [0.0, 0.0, 0.25, -0.5]);
+        let mut frame = [1i16; 4];
+        write_frame(&mut frame, [0.5, -0.5], [3, 0]);
+        assert_eq!(frame, [-16384, 0, 0, 16384]);
+        let mut mono = [1.0f32];
+        write_frame(&mut mono, [0.25, 0.25], [0, 1]);
+        assert_eq!(mono, [0.25]);
+    }
+
+    #[test]
+    fn slow_enumerator_is_cut_off_at_the_deadline() {
+        use std::time::{Duration, Instant};
+        let in_flight = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let (items, truncated) = collect_with_timeout(Duration::from_millis(100), &in_flight, |tx| {
+            let _ = tx.send(1);
+            let _ = tx.send(2);
+            // 応答しなくなったドライバ
+            std::thread::sleep(Duration::from_millis(500));
+            let _ = tx.send(3);
+        });
+        assert_eq!((items, truncated), (vec![1, 2], true));
+        assert!(started.elapsed() < Duration::from_millis(400), "{:?}", started.elapsed());
+        // 止まったままのスレッドがある間は新しく列挙しない
+        let (items, truncated) = collect_with_timeout(Duration::from_millis(100), &in_flight, |tx| { let _ = tx.send(9); });
+        assert_eq!((items, truncated), (vec![], true));
+
+        while in_flight.load(Ordering::Acquire) {
+            assert!(started.elapsed() < Duration::from_secs(5), "enumeration thread never finished");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let (items, truncated) = collect_with_timeout(Duration::from_millis(100), &in_flight, |tx| {
+            for i in 1..=3 {
+                let _ = tx.send(i);
+            }
+        });
+        assert_eq!((items, truncated), (vec![1, 2, 3], false));
+    }
+
+    #[test]
+    fn zero_width_collapses_to_mono() {
+        for frame in [[0.5, -0.25], [1.0, 0.0], [-0.3, 0.7]] {
+            let [l, r] = apply_width(frame, 0.0);
+            assert_eq!(l, r);
+            assert_eq!(l, (frame[0] + frame[1]) * 0.5);
+            assert_eq!(apply_width(frame, 1.0), frame);
+        }
+        // 2倍に広げるとサイド成分だけが倍になる
+        assert_eq!(apply_width([0.5, -0.25], 2.0), [0.875, -0.625]);
+    }
+
+    #[test]
+    fn device_snapshots_diff_into_added_and_removed() {
+        let ids = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let before = ids(&["usb-mic", "speakers", "hdmi"]);
+        let after = ids(&["speakers", "headset", "usb-mic", "bluetooth"]);
+        assert_eq!(diff_device_ids(&before, &after), (ids(&["bluetooth", "headset"]), ids(&["hdmi"])));
+        // 並び順が変わっただけなら変化なし
+        let reordered = ids(&["hdmi", "usb-mic", "speakers"]);
+        assert_eq!(diff_device_ids(&before, &reordered), (vec![], vec![]));
+    }
+
+    #[test]
+    fn missing_devices_are_reported() {
+        let issues = validate_config(None, "no-such-input", "no-such-output", None, None, None).unwrap();
+        assert_eq!(issues.len(), 2);
+        assert!(issues.iter().all(|i| matches!(i.severity, IssueSeverity::Error)));
+        assert!(issues[0].message.contains("no-such-input") && issues[1].message.contains("no-such-output"));
+    }
+
+    #[test]
+    fn unsupported_rate_and_buffer_size_are_errors() {
+        let range = cpal::SupportedStreamConfigRange::new(
+            2, 44100, 48000, cpal::SupportedBufferSize::Range { min: 64, max: 1024 }, cpal::SampleFormat::F32,
+        );
+        assert!(supports_config(&range, 48000, Some(256)));
+        assert!(supports_config(&range, 44100, None));
+        assert!(!supports_config(&range, 96000, Some(256)));
+        assert!(!supports_config(&range, 48000, Some(2048)));
+        assert!(output_config_issue(std::slice::from_ref(&range), 48000, Some(256)).is_none());
+        let issue = output_config_issue(&[range], 96000, None).unwrap();
+        assert!(matches!(issue.severity, IssueSeverity::Error) && issue.message.contains("96000 Hz"));
+    }
+
+    #[test]
+    fn mono_only_output_is_an_error() {
+        let mono = cpal::SupportedStreamConfigRange::new(1, 48000, 48000, cpal::SupportedBufferSize::Unknown, cpal::SampleFormat::F32);
+        let issue = output_config_issue(&[mono], 48000, None).unwrap();
+        assert!(matches!(issue.severity, IssueSeverity::Error) && issue.message.contains("stereo"));
+    }
+
+    #[test]
+    fn short_latency_is_flagged() {
+        // 10 ms = 480フレームでは1024フレームのバッファが入らない
+        assert!(matches!(latency_issue(10, 1024, 48000, 2), Some(ConfigIssue { severity: IssueSeverity::Error, .. })));
+        // 1バッファは入るが2バッファ分 (22 ms) の余裕はない
+        assert!(matches!(latency_issue(15, 512, 48000, 2), Some(ConfigIssue { severity: IssueSeverity::Warning, .. })));
+        assert!(latency_issue(22, 512, 48000, 2).is_none());
+    }
+
+    #[test]
+    fn failed_start_still_emits_finished() {
+        use tauri::Listener;
+        let app = tauri::test::mock_app();
+        let (tx, rx) = std::sync::mpsc::channel();
+        for event in ["error", "finished"] {
+            let tx = tx.clone();
+            app.listen_any(event, move |e| { let _ = tx.send((event, e.payload().to_owned())); });
+        }
+        let timeout = std::time::Duration::from_secs(1);
+        report_finished(app.handle(), "test", Err(AudioError::StreamFailed("failed to build output stream".to_owned())));
+        let (event, payload) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(event, "error");
+        assert!(payload.contains("failed to build output stream"), "{}", payload);
+        let (event, payload) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(event, "finished");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&payload).unwrap(), serde_json::json!({ "isFinished": true }));
+
+        report_finished(app.handle(), "test", Ok(()));
+        assert_eq!(rx.recv_timeout(timeout).unwrap().0, "finished");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn device_fade_ramps_out_and_back_in() {
+        let mut fade = new_device_fade(1.0, 48000.0);
+        fade.set_target(0.0);
+        let out: Vec<f64> = (0..1000).map(|_| fade.next_value()).collect();
+        // 20 ms = 960フレームで無音になり、途中で段差はできない
+        assert!(out.windows(2).all(|w| w[1] <= w[0] && w[0] - w[1] < 0.002));
+        assert!(out[958] > 0.0);
+        assert_eq!(out[959], 0.0);
+        fade.set_target(1.0);
+        let back: Vec<f64> = (0..960).map(|_| fade.next_value()).collect();
+        assert!(back.windows(2).all(|w| w[1] >= w[0]));
+        assert_eq!(back[959], 1.0);
+    }
+
+    #[test]
+    fn mono_device_gets_the_downmix() {
+        let sig = [0.8, -0.2];
+        for format in [OutputFormat::Stereo, OutputFormat::MidSide] {
+            let mut frame = [0i16];
+            write_frame(&mut frame, output_mix(sig, 1, format), [0, 1]);
+            assert_eq!(frame, [(0.3 * 32768.0) as i16]);
+        }
+        assert_eq!(output_mix(sig, 2, OutputFormat::Stereo), sig);
+    }
+
+    #[test]
+    fn output_starts_under_a_rising_envelope() {
+        let mut ramp = fade_in_ramp(15.0, 48000.0);
+        let out: Vec<f64> = (0..1000).map(|_| 0.5 * ramp.next_value()).collect();
+        // 15 ms = 720フレームで立ち上がり、その後の利得は変わらない
+        assert!(out[0] < 0.001);
+        assert!(out[..720].windows(2).all(|w| w[1] > w[0]));
+        assert!(out[720..].iter().all(|&x| x == 0.5));
+        // 0 msなら最初から素通し
+        assert_eq!(fade_in_ramp(0.0, 48000.0).next_value(), 1.0);
+    }
+
+    #[test]
+    fn identical_ids_are_a_feedback_risk() {
+        let reason = feedback_reason("hw:0", "hw:0", Some("Speakers"));
+        assert_eq!(reason.as_deref(), Some("input and output are the same device"));
+        // 名前が取れなくてもidが同じなら警告する
+        assert!(feedback_reason("hw:0", "hw:0", None).is_some());
+        assert!(feedback_reason("hw:1", "hw:0", Some("Monitor of Speakers")).is_some());
+        assert!(feedback_reason("hw:1", "hw:0", Some("USB Microphone")).is_none());
+        assert!(feedback_reason("hw:1", "hw:0", None).is_none());
+    }
+
+    #[test]
+    fn starved_consumer_yields_no_frame() {
+        let (mut prod, mut cons) = ringbuf::HeapRb::<f32>::new(16).split();
+        prod.push_slice(&[0.1, 0.2, 0.3]);
+        assert_eq!(pop_frame(&mut cons, 2), Some([0.1, 0.2]));
+        // 半端な1サンプルは読まずに残し、前のフレームを繰り返さない
+        assert_eq!(pop_frame(&mut cons, 2), None);
+        assert_eq!(cons.occupied_len(), 1);
+        prod.push_slice(&[0.4]);
+        assert_eq!(pop_frame(&mut cons, 2), Some([0.3, 0.4]));
+        assert_eq!(pop_frame(&mut cons, 2), None);
+
+        prod.push_slice(&[1.0, 2.0, 3.0, 4.0, 0.5]);
+        assert_eq!(pop_frame(&mut cons, 4), Some([1.0, 2.0]));
+        assert_eq!(pop_frame(&mut cons, 1), Some([0.5, 0.5]));
+        assert_eq!(pop_frame(&mut cons, 1), None);
+    }
+
+    #[test]
+    fn capabilities_reflect_the_build() {
+        let caps = serde_json::to_value(get_capabilities()).unwrap();
+        assert_eq!(caps["version"], env!("CARGO_PKG_VERSION"));
+        let features = [
+            ("hrtf", cfg!(feature = "hrtf")),
+            ("resampling", cfg!(feature = "resampling")),
+            ("recording", cfg!(feature = "recording")),
+        ];
+        for (key, enabled) in features {
+            assert_eq!(caps[key], enabled, "{}", key);
+        }
+        let formats = caps["sampleFormats"].as_array().unwrap();
+        assert_eq!(formats.len(), SUPPORTED_SAMPLE_FORMATS.len());
+        assert!(formats.contains(&serde_json::json!("f32")));
+        // JACKはjackフィーチャー付きでビルドしたときだけ現れる
+        let has_jack = caps["hosts"].as_array().unwrap().iter().any(|h| h.as_str().unwrap().eq_ignore_ascii_case("jack"));
+        if !cfg!(feature = "jack") {
+            assert!(!has_jack);
+        }
+    }
+
+    #[test]
+    fn exclusive_mode_falls_back_without_asio() {
+        assert!(matches!(resolve_stream_mode(StreamMode::Exclusive, "ASIO"), (StreamMode::Exclusive, None)));
+        let (mode, reason) = resolve_stream_mode(StreamMode::Exclusive, "WASAPI");
+        assert!(matches!(mode, StreamMode::Shared));
+        assert_eq!(reason.as_deref(), Some("exclusive mode is not available on WASAPI; using shared mode"));
+        // 共有モードの要求はどのホストでもそのまま通す
+        for host in ["ASIO", "ALSA"] {
+            assert!(matches!(resolve_stream_mode(StreamMode::Shared, host), (StreamMode::Shared, None)));
+        }
+    }
+
+    #[test]
+    fn dry_lowpass_removes_highs_from_the_dry_path() {
+        // 1秒ぶんの正弦波を通し、後半の左チャンネルのピークを見る
+        let peak = |cutoff: Option<f32>, freq: f32| {
+            let mut lowpass = new_dry_lowpass(cutoff, 48000.0);
+            (0..48000).map(|n| {
+                let x = (2.0 * std::f32::consts::PI * freq * n as f32 / 48000.0).sin() * 0.5;
+                filter_dry(&mut lowpass, [x, x])[0].abs()
+            }).skip(24000).fold(0.0, f32::max)
+        };
+        assert!(peak(Some(500.0), 6000.0) < 0.5 * 0.01, "{}", peak(Some(500.0), 6000.0));
+        assert!((peak(Some(500.0), 50.0) - 0.5).abs() < 0.01);
+        // 設定しなければドライも素通し
+        assert!((peak(None, 6000.0) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn buffer_size_is_kept_on_other_hosts() {
+        for &host_id in cpal::ALL_HOSTS.iter().filter(|&&id| !is_jack_host(id)) {
+            assert_eq!(resolve_buffer_size(host_id, Some(256)), (cpal::BufferSize::Fixed(256), None));
+            assert_eq!(resolve_buffer_size(host_id, None), (cpal::BufferSize::Default, None));
+        }
+    }
+
+    #[cfg(feature = "jack")]
+    #[test]
+    fn jack_ignores_buffer_size() {
+        let host_id = "jack".parse::<cpal::HostId>().expect("jack host is compiled in");
+        assert!(is_jack_host(host_id));
+        let (buffer_size, ignored) = resolve_buffer_size(host_id, Some(256));
+        assert_eq!(buffer_size, cpal::BufferSize::Default);
+        assert!(ignored.is_some());
+    }
+
+    #[test]
+    fn bypass_crossfade_reaches_the_raw_input() {
+        let (processed, raw) = ([0.2, -0.2], [0.6, 0.6]);
+        let mut bypass = new_bypass_fade(0.0, 48000.0);
+        bypass.set_target(1.0);
+        // 30 ms = 1440フレームかけて入力に移る
+        let out: Vec<[f32; 2]> = (0..1500).map(|_| crossfade_bypass(processed, raw, bypass.next_value() as f32)).collect();
+        assert!((out[0][0] - processed[0]).abs() < 0.001);
+        assert!(out.windows(2).all(|w| w[1][0] >= w[0][0] && w[1][1] >= w[0][1]));
+        assert!(out[1438] != raw);
+        assert!(out[1439..].iter().all(|&f| f == raw));
+        // 戻すときも同じ長さで処理後の信号に戻る
+        bypass.set_target(0.0);
+        let back: Vec<[f32; 2]> = (0..1440).map(|_| crossfade_bypass(processed, raw, bypass.next_value() as f32)).collect();
+        assert_eq!(back[1439], processed);
+    }
+
+    #[test]
+    fn routing_flag_follows_start_and_abort() {
+        let app = tauri::test::mock_app();
+        app.manage(AppState::new());
+        let state = app.state::<AppState>();
+        assert!(!is_routing_active(app.state()));
+        // set_audio_devicesと同じ順に立てる
+        state.abort_signal.store(false, Ordering::Relaxed);
+        state.running.store(true, Ordering::Relaxed);
+        assert!(is_routing_active(app.state()));
+        // 停止を要求した時点で、スレッドが終わる前でも非アクティブとして返す
+        abort_audio_routing(app.handle().clone(), app.state()).unwrap();
+        assert!(!is_routing_active(app.state()));
+        state.running.store(false, Ordering::Relaxed);
+        assert!(!is_routing_active(app.state()));
+        // スレッドが終わったあとに再開しても正しく戻る
+        state.abort_signal.store(false, Ordering::Relaxed);
+        assert!(!is_routing_active(app.state()));
+        state.running.store(true, Ordering::Relaxed);
+        assert!(is_routing_active(app.state()));
+    }
+
+    #[test]
+    fn mid_side_encoding_of_a_known_pair() {
+        let [mid, side] = encode_mid_side([0.6, 0.2]);
+        assert!((mid - 0.8 / 2f32.sqrt()).abs() < 1e-6, "{}", mid);
+        assert!((side - 0.4 / 2f32.sqrt()).abs() < 1e-6, "{}", side);
+        // 同相はサイドが0、逆相はミッドが0になる
+        assert_eq!(encode_mid_side([0.5, 0.5])[1], 0.0);
+        assert_eq!(encode_mid_side([0.5, -0.5])[0], 0.0);
+        assert_eq!(output_mix([0.6, 0.2], 2, OutputFormat::MidSide), encode_mid_side([0.6, 0.2]));
+    }
+
+    #[test]
+    fn i32_output_is_supported() {
+        assert!(ensure_sample_format(cpal::SampleFormat::I32).is_ok());
+        // start_thru::<i32>と同じ変換で書き込む
+        let mut frame = [1i32; 2];
+        write_frame(&mut frame, [0.5, -0.5], [0, 1]);
+        assert_eq!(frame, [1 << 30, -(1 << 30)]);
+    }
+
+    #[test]
+    fn stop_routing_joins_the_worker() {
+        let state = AppState::new();
+        // 動いていなければ何もせずに戻る
+        stop_routing(&state);
+        state.abort_signal.store(false, Ordering::Relaxed);
+        state.running.store(true, Ordering::Relaxed);
+        let (abort, running) = (Arc::clone(&state.abort_signal), Arc::clone(&state.running));
+        let worker = std::thread::spawn(move || {
+            while !abort.load(Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            running.store(false, Ordering::Relaxed);
+        });
+        *state.routing_thread.lock().unwrap() = Some(worker);
+        stop_routing(&state);
+        // 戻った時点でスレッドは終わっていて、ハンドルも残らない
+        assert!(!state.running.load(Ordering::Relaxed));
+        assert!(state.routing_thread.lock().unwrap().is_none());
+        stop_routing(&state);
+    }
+
+    #[test]
+    fn swapped_channels_are_mirrored() {
+        let mut frame = [0.0f32; 2];
+        write_frame(&mut frame, [0.25, -0.5], output_channel_map([0, 1], true));
+        assert_eq!(frame, [-0.5, 0.25]);
+        write_frame(&mut frame, [0.25, -0.5], output_channel_map([0, 1], false));
+        assert_eq!(frame, [0.25, -0.5]);
+        // 任意のマッピングでも割り当てだけが入れ替わる
+        assert_eq!(output_channel_map([2, 5], true), [5, 2]);
+    }
+
+    #[test]
+    fn post_process_gain_scales_only_the_output() {
+        let render = |stage: GainStage, gain: f64| {
+            let mut engine = ctc_engine::CtcEngine::new(&ctc_engine::EngineConfig {
+                sample_rate: 48000.0,
+                ct_delays: [10.0, 10.0],
+                main_delays: [0.0, 0.0],
+                lp_cutoffs: [2000.0, 2000.0],
+                hp_cutoff: 20.0,
+                ls_cutoff: 200.0,
+                ls_gain: 0.0,
+                dc_block: false,
+                allpass_order: 4,
+                shadow_stages: [1, 1],
+                custom_poles: None,
+            });
+            let mut master_gain = LinearSmoother::new(gain, 1);
+            let out: Vec<[f32; 2]> = (0..2400).map(|n| {
+                let x = (n as f32 * 0.05).sin() * 0.8;
+                let input = apply_master_gain(stage, GainStage::PreProcess, [x, 0.0], &mut master_gain);
+                let y = engine.process(input, 0.7, &[1.0; 4]);
+                apply_master_gain(stage, GainStage::PostProcess, y, &mut master_gain)
+            }).collect();
+            (out, engine.take_clip_counts())
+        };
+        let (unity, unity_clips) = render(GainStage::PostProcess, 1.0);
+        let (post, post_clips) = render(GainStage::PostProcess, 2.0);
+        // 後段の利得は打ち消し処理を通った出力をそのまま2倍にするだけで、エンジンの中のクリップは変わらない
+        assert!(post.iter().zip(&unity).all(|(p, u)| *p == [u[0] * 2.0, u[1] * 2.0]));
+        assert_eq!(post_clips, unity_clips);
+        let (_, pre_clips) = render(GainStage::PreProcess, 2.0);
+        assert!(pre_clips[0] > unity_clips[0], "{:?} vs {:?}", pre_clips, unity_clips);
+    }
+}
@@ -0,0 +1,30 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+// 標準エラー出力に書くだけの簡単なロガー。レベルはRUST_LOGで変えられる
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init() {
+    let default = if cfg!(debug_assertions) { LevelFilter::Debug } else { LevelFilter::Warn };
+    let level = std::env::var("RUST_LOG").ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
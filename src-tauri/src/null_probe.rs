@@ -0,0 +1,117 @@
+use crate::ctc_engine::{shadow_low_pass, LS_TO_LE, LS_TO_RE, RS_TO_LE, RS_TO_RE};
+use crate::filter::{BiquadCascade, Processable};
+
+const BUFFER_LEN: usize = 512;
+
+// スピーカー出力から両耳に届く信号を、エンジンと同じ幾何モデル (振幅係数・クロストーク遅延・頭部の遮蔽) で見積もる。
+// 打ち消しが理想どおりなら、各耳には同じ側のスピーカーの直接音だけが届く
+pub struct NullProbe {
+    sample_rate: f32,
+    shadow_stages: [usize; 2],
+    amp_factors: [f64; 4],
+    ct_delays: [f64; 2],
+    shadow_l: BiquadCascade,
+    shadow_r: BiquadCascade,
+    rb_l: [f64; BUFFER_LEN],
+    rb_r: [f64; BUFFER_LEN],
+    idx: usize,
+}
+
+impl NullProbe {
+    pub fn new(sample_rate: f32, amp_factors: [f64; 4], ct_delays: [f64; 2], shadow_cutoffs: [f32; 2], shadow_stages: [usize; 2]) -> Self {
+        Self {
+            sample_rate,
+            shadow_stages,
+            amp_factors,
+            ct_delays: clamp_delays(ct_delays),
+            shadow_l: shadow_low_pass(sample_rate, shadow_cutoffs[0], shadow_stages[0]),
+            shadow_r: shadow_low_pass(sample_rate, shadow_cutoffs[1], shadow_stages[1]),
+            rb_l: [0.0; BUFFER_LEN],
+            rb_r: [0.0; BUFFER_LEN],
+            idx: 0,
+        }
+    }
+
+    // 聴取位置やカットオフが変わったときに、エンジンと同じ経路のモデルに合わせ直す
+    pub fn set_paths(&mut self, amp_factors: [f64; 4], ct_delays: [f64; 2], shadow_cutoffs: [f32; 2]) {
+        self.amp_factors = amp_factors;
+        self.ct_delays = clamp_delays(ct_delays);
+        let sr = self.sample_rate;
+        self.shadow_l = shadow_low_pass(sr, shadow_cutoffs[0], self.shadow_stages[0]);
+        self.shadow_r = shadow_low_pass(sr, shadow_cutoffs[1], self.shadow_stages[1]);
+    }
+
+    fn read(&self, buffer: &[f64; BUFFER_LEN], delay: f64) -> f64 {
+        let read_pos = self.idx as f64 - delay;
+        let pos_floor = read_pos.floor();
+        let idx_a = (pos_floor as i64).rem_euclid(BUFFER_LEN as i64) as usize;
+        let idx_b = (idx_a + 1) % BUFFER_LEN;
+        let frac = read_pos - pos_floor;
+        buffer[idx_a] + frac * (buffer[idx_b] - buffer[idx_a])
+    }
+
+    // outputは処理済みのスピーカー出力、referenceは直接音だけを出したときのスピーカー出力。
+    // 耳に届く信号と、直接音だけが届いた場合との差 (残差) を返す
+    pub fn process(&mut self, [out_l, out_r]: [f32; 2], [ref_l, ref_r]: [f32; 2]) -> [f32; 2] {
+        let a = &self.amp_factors;
+        let leak_to_le = self.read(&self.rb_r, self.ct_delays[1]);
+        let leak_to_re = self.read(&self.rb_l, self.ct_delays[0]);
+        self.rb_l[self.idx] = self.shadow_l.process(out_l as f64);
+        self.rb_r[self.idx] = self.shadow_r.process(out_r as f64);
+        self.idx = (self.idx + 1) % BUFFER_LEN;
+
+        let ear_l = out_l as f64 * a[LS_TO_LE] + leak_to_le * a[RS_TO_LE];
+        let ear_r = out_r as f64 * a[RS_TO_RE] + leak_to_re * a[LS_TO_RE];
+        [
+            (ear_l - ref_l as f64 * a[LS_TO_LE]) as f32,
+            (ear_r - ref_r as f64 * a[RS_TO_RE]) as f32,
+        ]
+    }
+}
+
+fn clamp_delays(ct_delays: [f64; 2]) -> [f64; 2] {
+    ct_delays.map(|d| d.clamp(1.0, (BUFFER_LEN - 3) as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 48000.0;
+    const DELAY: usize = 10;
+
+    // 左入力だけのときに、プローブと同じ経路モデルを完全に打ち消す出力を作って残差の最大値を返す
+    fn max_residual(cancel: bool) -> f32 {
+        let mut a = [0.0; 4];
+        a[LS_TO_LE] = 1.0;
+        a[LS_TO_RE] = 0.6;
+        a[RS_TO_LE] = 0.5;
+        a[RS_TO_RE] = 0.9;
+        let mut probe = NullProbe::new(SR, a, [DELAY as f64; 2], [2000.0; 2], [1, 1]);
+        let mut shadow = [0, 1].map(|_| shadow_low_pass(SR, 2000.0, 1));
+        // 遮蔽を掛けた後のスピーカー出力の履歴
+        let mut shadowed = [vec![0.0f64; DELAY], vec![0.0f64; DELAY]];
+        let mut max: f32 = 0.0;
+        for n in 0..4800 {
+            let x = (n as f32 * 0.05).sin() * 0.5;
+            let (leak_l, leak_r) = (shadowed[0][n], shadowed[1][n]);
+            let (out_l, out_r) = if cancel {
+                ((x as f64 - leak_r * a[RS_TO_LE] / a[LS_TO_LE]) as f32, (-leak_l * a[LS_TO_RE] / a[RS_TO_RE]) as f32)
+            } else {
+                (x, 0.0)
+            };
+            shadowed[0].push(shadow[0].process(out_l as f64));
+            shadowed[1].push(shadow[1].process(out_r as f64));
+            let residual = probe.process([out_l, out_r], [x, 0.0]);
+            max = max.max(residual[0].abs()).max(residual[1].abs());
+        }
+        max
+    }
+
+    #[test]
+    fn perfect_cancellation_leaves_no_residual() {
+        assert!(max_residual(true) < 1e-6, "{}", max_residual(true));
+        // 打ち消さなければクロストークがそのまま残る
+        assert!(max_residual(false) > 0.1, "{}", max_residual(false));
+    }
+}
@@ -0,0 +1,36 @@
+// 出力の開始時に、リングバッファが目標の量まで溜まるのを待つ
+pub struct Primer {
+    threshold: usize,
+    primed: bool,
+}
+
+impl Primer {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold, primed: threshold == 0 }
+    }
+
+    // 一度しきい値に達したら、以降は溜まっている量にかかわらずtrueを返す
+    pub fn update(&mut self, occupied: usize) -> bool {
+        if !self.primed && occupied >= self.threshold {
+            self.primed = true;
+        }
+        self.primed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primer_activates_at_the_threshold() {
+        let mut primer = Primer::new(480);
+        assert!(!primer.update(0));
+        assert!(!primer.update(479));
+        assert!(primer.update(480));
+        // 一度始まったら、減っても無音待ちには戻らない
+        assert!(primer.update(0));
+        // しきい値0では最初から出力する
+        assert!(Primer::new(0).update(0));
+    }
+}
@@ -0,0 +1,75 @@
+// 入出力のサンプルレートが異なるときに使う、Catmull-Rom補間による簡易的なレート変換
+pub struct Resampler {
+    step: f64,
+    pos: f64,
+    history: [[f32; 2]; 4],
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            step: input_rate as f64 / output_rate as f64,
+            // 最初の呼び出しで履歴を埋めるため、読み進める位置から始める
+            pos: 3.0,
+            history: [[0.0; 2]; 4],
+        }
+    }
+
+    // 入力が足りないときはNoneを返し、状態は進めない (次の呼び出しで続きから読む)
+    pub fn next(&mut self, mut pull: impl FnMut() -> Option<[f32; 2]>) -> Option<[f32; 2]> {
+        while self.pos >= 1.0 {
+            let frame = pull()?;
+            self.history.rotate_left(1);
+            self.history[3] = frame;
+            self.pos -= 1.0;
+        }
+        let t = self.pos as f32;
+        let [p0, p1, p2, p3] = self.history;
+        let out = [0, 1].map(|c| {
+            let (p0, p1, p2, p3) = (p0[c], p1[c], p2[c], p3[c]);
+            p1 + 0.5 * t * (p2 - p0 + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + t * (3.0 * (p1 - p2) + p3 - p0)))
+        });
+        self.pos += self.step;
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample_all(input_rate: u32, output_rate: u32, input: &[[f32; 2]]) -> Vec<[f32; 2]> {
+        let mut resampler = Resampler::new(input_rate, output_rate);
+        let mut frames = input.iter().copied();
+        std::iter::from_fn(|| resampler.next(|| frames.next())).collect()
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        let input = vec![[0.0f32; 2]; 44100];
+        for (from, to) in [(44100, 48000), (48000, 44100), (48000, 96000), (44100, 44100)] {
+            let out = resample_all(from, to, &input);
+            let expected = input.len() as f64 * to as f64 / from as f64;
+            assert!((out.len() as f64 - expected).abs() <= 4.0, "{} -> {}: {} frames, expected {}", from, to, out.len(), expected);
+        }
+    }
+
+    #[test]
+    fn starved_input_resumes_where_it_stopped() {
+        let input: Vec<[f32; 2]> = (0..4800).map(|n| {
+            let x = (n as f32 * 0.01).sin();
+            [x, -x]
+        }).collect();
+        let whole = resample_all(44100, 48000, &input);
+        // 入力が128フレームずつしか届かない場合も、まとめて渡したときと同じ出力になる
+        let mut resampler = Resampler::new(44100, 48000);
+        let mut chunked = Vec::new();
+        for chunk in input.chunks(128) {
+            let mut frames = chunk.iter().copied();
+            while let Some(frame) = resampler.next(|| frames.next()) {
+                chunked.push(frame);
+            }
+        }
+        assert_eq!(chunked, whole);
+    }
+}
@@ -0,0 +1,105 @@
+// 部屋の寸法から求めた少数のタップで初期反射を足す (キャンセル処理の後段に掛ける)
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomParams {
+    // 幅・奥行き・高さ (m)
+    pub width: f32,
+    pub depth: f32,
+    pub height: f32,
+    // 壁の反射率 (0で反射なし、1で全反射)
+    pub reflectivity: f32,
+}
+
+pub const MAX_ROOM_DIMENSION: f32 = 50.0;
+// 何次の鏡像まで足すか
+const REFLECTION_ORDER: usize = 2;
+// 直接音の距離の目安 (m)。反射の減衰を逆距離則で見積もるのに使う
+const REFERENCE_DISTANCE: f64 = 1.0;
+
+struct Tap {
+    delay: f64,
+    gain: f64,
+}
+
+pub struct EarlyReflections {
+    taps: Vec<Tap>,
+    buffer_l: Vec<f64>,
+    buffer_r: Vec<f64>,
+    mask: usize,
+    idx: usize,
+}
+
+impl EarlyReflections {
+    pub fn new(sample_rate: f32, params: &RoomParams, speed_of_sound: f64) -> Self {
+        // 聴取位置を部屋の中央とみなすと、各面での反射の行路差はn次で寸法のn倍になる
+        let mut taps = Vec::new();
+        for dimension in [params.width, params.depth, params.height] {
+            for order in 1..=REFLECTION_ORDER {
+                let extra = dimension as f64 * order as f64;
+                taps.push(Tap {
+                    delay: extra / speed_of_sound * sample_rate as f64,
+                    gain: (params.reflectivity as f64).powi(order as i32) * REFERENCE_DISTANCE / (REFERENCE_DISTANCE + extra),
+                });
+            }
+        }
+        let max_delay = taps.iter().map(|t| t.delay).fold(0.0, f64::max);
+        let len = (max_delay.ceil() as usize + 2).next_power_of_two();
+        Self {
+            taps,
+            buffer_l: vec![0.0; len],
+            buffer_r: vec![0.0; len],
+            mask: len - 1,
+            idx: 0,
+        }
+    }
+
+    // CtcEngineの遅延線と同じく、端数の遅延は線形補間で読む
+    #[inline(always)]
+    fn read(&self, buffer: &[f64], delay: f64) -> f64 {
+        let read_pos = self.idx as f64 - delay;
+        let pos_floor = read_pos.floor();
+        let idx_a = (pos_floor as i64).rem_euclid(buffer.len() as i64) as usize;
+        let idx_b = (idx_a + 1) & self.mask;
+        let frac = read_pos - pos_floor;
+        buffer[idx_a] + frac * (buffer[idx_b] - buffer[idx_a])
+    }
+
+    pub fn process(&mut self, [l, r]: [f32; 2]) -> [f32; 2] {
+        self.buffer_l[self.idx] = l as f64;
+        self.buffer_r[self.idx] = r as f64;
+        let mut out = [l as f64, r as f64];
+        for tap in &self.taps {
+            out[0] += tap.gain * self.read(&self.buffer_l, tap.delay);
+            out[1] += tap.gain * self.read(&self.buffer_r, tap.delay);
+        }
+        self.idx = (self.idx + 1) & self.mask;
+        out.map(|x| x as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reflections_appear_at_the_path_differences() {
+        // 音速480 m/sなら1 mが100サンプルになる
+        let room = RoomParams { width: 1.0, depth: 2.0, height: 3.0, reflectivity: 0.5 };
+        let mut er = EarlyReflections::new(48000.0, &room, 480.0);
+        let out: Vec<[f32; 2]> = (0..700).map(|n| er.process([(n == 0) as u8 as f32, 0.0])).collect();
+        // 反射率^次数 / (1 + 行路差)。200サンプルには幅の2次と奥行きの1次が重なる
+        let expected = [
+            (0, 1.0),
+            (100, 0.5 / 2.0),
+            (200, 0.25 / 3.0 + 0.5 / 3.0),
+            (300, 0.5 / 4.0),
+            (400, 0.25 / 5.0),
+            (600, 0.25 / 7.0),
+        ];
+        for (n, frame) in out.iter().enumerate() {
+            let want = expected.iter().find(|(i, _)| *i == n).map_or(0.0, |&(_, g)| g);
+            assert!((frame[0] as f64 - want).abs() < 1e-6, "n {}: {} != {}", n, frame[0], want);
+            assert_eq!(frame[1], 0.0);
+        }
+    }
+}
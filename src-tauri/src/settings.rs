@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+use crate::transaural_core::PositionCoords;
+
+const LAST_SETTINGS_FILE: &str = "last_settings.json";
+
+// 前回のセッションの設定。set_audio_devicesに渡す基本の引数と同じ形で、フロントエンドの入力欄にそのまま戻せる
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PresetParams {
+    #[serde(default)]
+    pub host_id: Option<String>,
+    pub input_id: String,
+    pub output_id: String,
+    pub latency: usize,
+    pub position: PositionCoords,
+    pub master_gain: f32,
+    pub attenuation: f32,
+    pub lowpass_cutoff_min: f32,
+    pub highpass_cutoff: f32,
+    pub lowshelf_cutoff: f32,
+    pub lowshelf_gain: f32,
+    pub wet_dry: f32,
+    pub temperature: f32,
+}
+
+fn last_settings_path(config_dir: &Path) -> PathBuf {
+    config_dir.join(LAST_SETTINGS_FILE)
+}
+
+pub fn save_last(config_dir: &Path, settings: &PresetParams) -> Result<(), String> {
+    std::fs::create_dir_all(config_dir).map_err(|e| format!("{}: {}", config_dir.display(), e))?;
+    let path = last_settings_path(config_dir);
+    let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    // 書き込み途中で終了しても壊れたファイルが残らないよう、一時ファイルから置き換える
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, json).map_err(|e| format!("{}: {}", tmp.display(), e))?;
+    std::fs::rename(&tmp, &path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// まだ保存されていなければNone。形の合わないファイルはエラーにする
+pub fn load_last(config_dir: &Path) -> Result<Option<PresetParams>, String> {
+    let path = last_settings_path(config_dir);
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    serde_json::from_str(&json).map(Some).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_settings_round_trip() {
+        let dir = std::env::temp_dir().join(format!("transaural-settings-{}", std::process::id()));
+        assert!(load_last(&dir).unwrap().is_none());
+        let settings: PresetParams = serde_json::from_value(serde_json::json!({
+            "hostId": null,
+            "inputId": "hw:1",
+            "outputId": "hw:0",
+            "latency": 100,
+            "position": {
+                "leftSpeaker": [-0.3, 1.0, 0.0], "rightSpeaker": [0.3, 1.0, 0.0],
+                "leftEar": [-0.08, 0.0, 0.0], "rightEar": [0.08, 0.0, 0.0], "headRadius": 0.0,
+            },
+            "masterGain": 0.75,
+            "attenuation": 0.7,
+            "lowpassCutoffMin": 800.0,
+            "highpassCutoff": 50.0,
+            "lowshelfCutoff": 200.0,
+            "lowshelfGain": 3.0,
+            "wetDry": 1.0,
+            "temperature": 20.0,
+        })).unwrap();
+        save_last(&dir, &settings).unwrap();
+        let loaded = load_last(&dir).unwrap().unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&settings).unwrap());
+        assert_eq!((loaded.input_id.as_str(), loaded.latency), ("hw:1", 100));
+        assert_eq!(loaded.position.left_speaker, [-0.3, 1.0, 0.0]);
+        // 壊れたファイルも、必要な項目が欠けたファイルもエラーにする
+        std::fs::write(last_settings_path(&dir), "{").unwrap();
+        assert!(load_last(&dir).is_err());
+        std::fs::write(last_settings_path(&dir), r#"{"inputId": "hw:1", "latency": "fast"}"#).unwrap();
+        assert!(load_last(&dir).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,67 @@
+pub struct LinearSmoother {
+    current: f64,
+    target: f64,
+    step: f64,
+    remaining: usize,
+    ramp_len: usize,
+}
+
+impl LinearSmoother {
+    pub fn new(initial: f64, ramp_len: usize) -> Self {
+        Self {
+            current: initial,
+            target: initial,
+            step: 0.0,
+            remaining: 0,
+            ramp_len: ramp_len.max(1),
+        }
+    }
+
+    pub fn set_target(&mut self, target: f64) {
+        if target == self.target { return; }
+        self.target = target;
+        self.remaining = self.ramp_len;
+        self.step = (target - self.current) / self.ramp_len as f64;
+    }
+
+    pub fn target(&self) -> f64 {
+        self.target
+    }
+
+    #[inline(always)]
+    pub fn next_value(&mut self) -> f64 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_change_ramps_monotonically() {
+        let mut s = LinearSmoother::new(0.25, 100);
+        s.set_target(1.0);
+        let ramp: Vec<f64> = (0..120).map(|_| s.next_value()).collect();
+        assert!(ramp.windows(2).all(|w| w[1] >= w[0]));
+        // 1サンプル目で段差にならず、ramp_len後にちょうど目標に着く
+        assert!(ramp[0] < 0.26);
+        assert!(ramp[98] < 1.0);
+        assert_eq!(ramp[99], 1.0);
+        assert_eq!(ramp[119], 1.0);
+    }
+
+    #[test]
+    fn retargeting_mid_ramp_starts_from_the_current_value() {
+        let mut s = LinearSmoother::new(0.0, 10);
+        s.set_target(1.0);
+        let mid = (0..5).map(|_| s.next_value()).last().unwrap();
+        s.set_target(0.0);
+        let next = s.next_value();
+        assert!(next < mid && next > 0.0);
+    }
+}
@@ -0,0 +1,70 @@
+use std::f32::consts::PI;
+
+use crate::fft::Fft;
+
+pub const FFT_SIZES: [usize; 3] = [512, 1024, 2048];
+
+pub struct SpectrumAnalyzer {
+    size: usize,
+    window: Vec<f32>,
+    window_sum: f32,
+    fft: Fft,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(size: usize) -> Self {
+        // ハン窓
+        let window: Vec<f32> = (0..size)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / size as f32).cos())
+            .collect();
+        let window_sum = window.iter().sum();
+        Self {
+            size,
+            window,
+            window_sum,
+            fft: Fft::new(size),
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+        }
+    }
+
+    // `block` (長さはFFTサイズ) の振幅スペクトルをdBFSで返す。ビン数はsize / 2 + 1
+    pub fn analyze(&mut self, block: &[f32]) -> Vec<f32> {
+        for (i, (x, w)) in block.iter().zip(&self.window).enumerate() {
+            self.re[i] = x * w;
+            self.im[i] = 0.0;
+        }
+        self.fft.forward(&mut self.re, &mut self.im);
+        let scale = 2.0 / self.window_sum;
+        (0..=self.size / 2)
+            .map(|k| {
+                let mag = (self.re[k] * self.re[k] + self.im[k] * self.im[k]).sqrt() * scale;
+                20.0 * mag.max(1e-10).log10()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_peaks_at_its_bin() {
+        let sample_rate = 48000.0;
+        for size in FFT_SIZES {
+            let mut analyzer = SpectrumAnalyzer::new(size);
+            let bin = size / 16;
+            let freq = bin as f32 * sample_rate / size as f32;
+            let block: Vec<f32> = (0..size).map(|n| 0.5 * (2.0 * PI * freq * n as f32 / sample_rate).sin()).collect();
+            let db = analyzer.analyze(&block);
+            assert_eq!(db.len(), size / 2 + 1);
+            let peak = (0..db.len()).max_by(|&a, &b| db[a].total_cmp(&db[b])).unwrap();
+            assert_eq!(peak, bin, "size {}", size);
+            // 振幅0.5は-6 dBFS
+            assert!((db[peak] + 6.02).abs() < 0.1, "size {}: {} dB", size, db[peak]);
+        }
+    }
+}
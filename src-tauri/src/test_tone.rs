@@ -0,0 +1,138 @@
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+use cpal::{FromSample, Sample, traits::{DeviceTrait, StreamTrait}};
+use dasp::{Signal, signal};
+
+use crate::AudioError;
+
+const TONE_AMPLITUDE: f64 = 0.25; // -12 dBFS
+const CLICK_PERIOD_MS: f64 = 500.0;
+const CLICK_LEN: usize = 48;
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TestTone {
+    Sine { freq: f64 },
+    PinkNoise,
+    // 左右交互のクリック (スピーカーの配線確認用)
+    LrClick,
+}
+
+enum ToneGenerator {
+    Sine(signal::Sine<signal::ConstHz>),
+    PinkNoise { noise: signal::Noise, state: [f64; 7] },
+    LrClick { pos: usize, period: usize },
+}
+
+impl ToneGenerator {
+    fn new(tone: TestTone, sample_rate: f64) -> Self {
+        match tone {
+            TestTone::Sine { freq } => ToneGenerator::Sine(signal::rate(sample_rate).const_hz(freq).sine()),
+            TestTone::PinkNoise => ToneGenerator::PinkNoise { noise: signal::noise(0), state: [0.0; 7] },
+            TestTone::LrClick => ToneGenerator::LrClick {
+                pos: 0,
+                period: (CLICK_PERIOD_MS * sample_rate / 1000.0) as usize,
+            },
+        }
+    }
+
+    fn next(&mut self) -> [f32; 2] {
+        match self {
+            ToneGenerator::Sine(sine) => {
+                let v = (sine.next() * TONE_AMPLITUDE) as f32;
+                [v, v]
+            }
+            ToneGenerator::PinkNoise { noise, state: b } => {
+                // Paul Kelletのフィルタで白色雑音を-3 dB/octにする
+                let white = noise.next();
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.1538520;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+                let v = (pink * 0.11 * TONE_AMPLITUDE) as f32;
+                [v, v]
+            }
+            ToneGenerator::LrClick { pos, period } => {
+                let phase = *pos % (*period * 2);
+                *pos += 1;
+                let v = if phase % *period < CLICK_LEN { TONE_AMPLITUDE as f32 } else { 0.0 };
+                if phase < *period { [v, 0.0] } else { [0.0, v] }
+            }
+        }
+    }
+}
+
+// processで生成した信号を加工してから出力する (エンジンを通す場合など)。on_tickは再生中に定期的に呼ばれる
+pub fn play<T, P, M>(
+    output: &cpal::Device,
+    config: &cpal::StreamConfig,
+    tone: TestTone,
+    duration: std::time::Duration,
+    abort_signal: Arc<AtomicBool>,
+    mut process: P,
+    mut on_tick: M,
+) -> Result<(), AudioError>
+where
+    T: cpal::SizedSample + FromSample<f32> + Send + 'static,
+    P: FnMut([f32; 2]) -> [f32; 2] + Send + 'static,
+    M: FnMut(),
+{
+    let channels = config.channels as usize;
+    let mut generator = ToneGenerator::new(tone, config.sample_rate as f64);
+    let output_fn = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        for frame in data.chunks_exact_mut(channels) {
+            let [l, r] = process(generator.next());
+            frame.fill(T::EQUILIBRIUM);
+            frame[0] = l.to_sample();
+            if let Some(s) = frame.get_mut(1) {
+                *s = r.to_sample();
+            }
+        }
+    };
+    let err_fn = |e: cpal::StreamError| log::error!("Stream error occured: {:?}", e);
+    let stream = output.build_output_stream(config, output_fn, err_fn, None)
+        .map_err(|e| AudioError::StreamFailed(format!("failed to build output stream: {}", e)))?;
+    stream.play().map_err(|e| AudioError::StreamFailed(format!("failed to play output stream: {}", e)))?;
+
+    let deadline = std::time::Instant::now() + duration;
+    while !abort_signal.load(Ordering::Relaxed) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        on_tick();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_has_the_requested_frequency() {
+        let sample_rate = 48000.0;
+        for freq in [100.0, 1000.0, 4321.0] {
+            let mut generator = ToneGenerator::new(TestTone::Sine { freq }, sample_rate);
+            let frames: Vec<[f32; 2]> = (0..sample_rate as usize).map(|_| generator.next()).collect();
+            // 1秒間の負から正への零交差の数が周波数になる
+            let crossings = frames.windows(2).filter(|w| w[0][0] < 0.0 && w[1][0] >= 0.0).count();
+            assert!((crossings as f64 - freq).abs() <= 1.0, "{} Hz: {} crossings", freq, crossings);
+            let peak = frames.iter().fold(0.0f32, |m, f| m.max(f[0].abs()));
+            assert!((peak as f64 - TONE_AMPLITUDE).abs() < 1e-3, "{}", peak);
+            assert!(frames.iter().all(|f| f[0] == f[1]));
+        }
+    }
+
+    #[test]
+    fn pink_noise_is_identical_on_both_channels() {
+        let mut generator = ToneGenerator::new(TestTone::PinkNoise, 48000.0);
+        let frames: Vec<[f32; 2]> = (0..48000).map(|_| generator.next()).collect();
+        // エンジンにはL/Rが同じモノラル信号として入る
+        assert!(frames.iter().all(|f| f[0] == f[1]));
+        assert!(frames.iter().all(|f| f[0].abs() < 1.0));
+        let rms = (frames.iter().map(|f| (f[0] as f64).powi(2)).sum::<f64>() / frames.len() as f64).sqrt();
+        assert!(rms > 0.01, "{}", rms);
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+// キーごとに一定時間だけ値を保持する
+pub struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: HashMap<K, (Instant, V)>,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: HashMap::new() }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_at(key, Instant::now())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.insert_at(key, value, Instant::now());
+    }
+
+    // 時刻を外から与える版。テストで実際に待たずに期限切れを確かめられる
+    fn get_at(&self, key: &K, now: Instant) -> Option<V> {
+        self.entries.get(key)
+            .filter(|(stored_at, _)| now.saturating_duration_since(*stored_at) < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert_at(&mut self, key: K, value: V, now: Instant) {
+        self.entries.insert(key, (now, value));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_value_is_served_within_the_ttl() {
+        let ttl = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut cache = TtlCache::new(ttl);
+        let mut enumerations = 0;
+        let mut lookup = |cache: &mut TtlCache<&str, Vec<u32>>, now: Instant| {
+            cache.get_at(&"output", now).unwrap_or_else(|| {
+                enumerations += 1;
+                let devices = vec![enumerations];
+                cache.insert_at("output", devices.clone(), now);
+                devices
+            })
+        };
+        assert_eq!(lookup(&mut cache, start), [1]);
+        // TTL内は列挙し直さない
+        assert_eq!(lookup(&mut cache, start + Duration::from_secs(1)), [1]);
+        assert_eq!(lookup(&mut cache, start + ttl - Duration::from_millis(1)), [1]);
+        // TTLちょうどで期限切れになる
+        assert_eq!(lookup(&mut cache, start + ttl), [2]);
+        // デバイスの変化で捨てられたら次は列挙し直す
+        cache.clear();
+        assert_eq!(lookup(&mut cache, start + ttl), [3]);
+        assert_eq!(cache.get(&"input"), None);
+    }
+}
@@ -0,0 +1,76 @@
+// PCM (16/24/32 bit) と32 bit浮動小数点のみ対応
+pub fn parse_wav(bytes: &[u8]) -> Result<(u32, Vec<Vec<f32>>), String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_owned());
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32_at(pos + 4) as usize;
+        let body = pos + 8;
+        if body + len > bytes.len() {
+            return Err("truncated chunk".to_owned());
+        }
+        if id == b"fmt " && len >= 16 {
+            format = Some((u16_at(body), u16_at(body + 2) as usize, u32_at(body + 4), u16_at(body + 14)));
+        } else if id == b"data" {
+            let Some((tag, channels, rate, bits)) = format else {
+                return Err("data chunk before fmt chunk".to_owned());
+            };
+            let width = bits as usize / 8;
+            if channels == 0 || width == 0 {
+                return Err("invalid fmt chunk".to_owned());
+            }
+            let decode = |s: &[u8]| -> Option<f32> {
+                match (tag, bits) {
+                    (1, 16) => Some(i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0),
+                    (1, 24) => Some((i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as f32 / 8388608.0),
+                    (1, 32) => Some(i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2147483648.0),
+                    (3, 32) => Some(f32::from_le_bytes([s[0], s[1], s[2], s[3]])),
+                    _ => None,
+                }
+            };
+            let mut out = vec![Vec::new(); channels];
+            for frame in bytes[body..body + len].chunks_exact(width * channels) {
+                for (c, s) in frame.chunks_exact(width).enumerate() {
+                    out[c].push(decode(s).ok_or_else(|| format!("unsupported format: tag {} / {} bit", tag, bits))?);
+                }
+            }
+            return Ok((rate, out));
+        }
+        // チャンクは偶数バイト境界に揃えられている
+        pos = body + len + (len & 1);
+    }
+    Err("no data chunk".to_owned())
+}
+
+// 32 bit浮動小数点のWAVにする。チャンネルの長さは揃っている前提
+pub fn encode_wav(sample_rate: u32, channels: &[Vec<f32>]) -> Vec<u8> {
+    let frames = channels.first().map_or(0, |c| c.len());
+    let block_align = 4 * channels.len() as u16;
+    let data_len = frames as u32 * block_align as u32;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&3u16.to_le_bytes());
+    out.extend_from_slice(&(channels.len() as u16).to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&32u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for i in 0..frames {
+        for c in channels {
+            out.extend_from_slice(&c[i].to_le_bytes());
+        }
+    }
+    out
+}
@@ -44,7 +44,7 @@ const state = new Proxy({
 });
 
 async function fetchAudioDevices() {
-	const devices: AudioDeviceDescription[] = await invoke("get_audio_devices");
+	const { devices }: AudioDeviceList = await invoke("get_audio_devices");
 	for (const d of devices) {
 		const opt = new Option(d.name, d.id, d.isDefault, d.isDefault);
 		if (d.driver) opt.dataset.driver = d.driver;
@@ -118,13 +118,22 @@ function saveConfig() {
 		temperature: temperatureInput?.valueAsNumber ?? 20,
 	};
 	localStorage.setItem("config", JSON.stringify(config));
+	invoke("save_last_settings", { settings: config })
+		.catch(e => console.error("Failed to save settings:", e));
 	return config;
 }
 
-function restoreConfig() {
+async function restoreConfig() {
+	const saved: InvokeOptions | null = await invoke("load_last_settings").catch(() => null);
 	const config = localStorage.getItem("config");
-	if (!config) return;
-	const options: InvokeOptions = JSON.parse(config);
+	if (saved) {
+		applyConfig(saved);
+	} else if (config) {
+		applyConfig(JSON.parse(config));
+	}
+}
+
+function applyConfig(options: InvokeOptions) {
 	if (inputSelect) inputSelect.value = options.inputId;
 	if (outputSelect) outputSelect.value = options.outputId;
 	if (latencyInput) latencyInput.valueAsNumber = options.latency;
@@ -155,14 +164,27 @@ function restoreConfig() {
 
 // init
 fetchAudioDevices();
-restoreConfig();
-updatePositionFigure();
+restoreConfig().then(updatePositionFigure);
+// 起動時にバックエンドが前回の設定を送ってくる (受け取れなかったときはrestoreConfigで取りに行く)
+listen<InvokeOptions>("last_settings", e => {
+	applyConfig(e.payload);
+	updatePositionFigure();
+});
+// 再読み込み前から動いているルーティングがあれば、ボタンの状態を合わせる
+invoke<boolean>("is_routing_active").then(active => {
+	if (!active) return;
+	state.turned = true;
+	listen<Payload>("finished", e => {
+		state.turned = !e.payload.isFinished;
+	});
+});
 
 (form?.["positions"] as HTMLFieldSetElement).addEventListener("change", updatePositionFigure);
 
 form?.addEventListener("submit", e => {
 	e.preventDefault();
 	if (state.turned) {
+		saveConfig();
 		invoke("abort_audio_routing");
 	} else {
 		const config = saveConfig();
@@ -6,11 +6,19 @@ type AudioDeviceDescription = {
 	isDefault: boolean,
 }
 
+type AudioDeviceList = {
+	devices: AudioDeviceDescription[],
+	truncated: boolean,
+}
+
+type Coord = [number, number] | [number, number, number];
+
 type PositionCoords = {
-	leftSpeaker: [number, number],
-	rightSpeaker: [number, number],
-	leftEar: [number, number],
-	rightEar: [number, number],
+	leftSpeaker: Coord,
+	rightSpeaker: Coord,
+	leftEar: Coord,
+	rightEar: Coord,
+	headRadius?: number,
 }
 
 type InvokeOptions = {
@@ -21,6 +29,7 @@ type InvokeOptions = {
 	masterGain: number,
 	attenuation: number,
 	lowpassCutoffMin: number,
+	lowpassCutoffMax?: number,
 	highpassCutoff: number,
 	lowshelfCutoff: number,
 	lowshelfGain: number,